@@ -8,6 +8,7 @@ extern crate bhyve_api;
 
 use bhyve_api::system::*;
 use bhyve_api::vm::*;
+use bhyve_api::vmx::VmxExitReason;
 
 use std::io::Write;
 use std::slice;
@@ -103,34 +104,45 @@ fn main() {
         let rip = vm.get_register(BSP, vm_reg_name::VM_REG_GUEST_RIP).unwrap();
         println!("RIP reg before run is {}", rip);
 
-        match vm.run(BSP).expect("failed to run VM") {
-            VmExit::IoOut(port, bytes, value) => {
+        let exit = vm.run(BSP).expect("failed to run VM");
+        println!("RIP after run is {}, instruction length is {}", exit.rip, exit.inst_length);
+
+        match exit.reason {
+            VmExit::IoOut { port, bytes, value } => {
                 let data: [u8; 4] = value.to_le_bytes();
                 println!("exit for IoOut, port={}, bytes={}, value={}", port, bytes, value);
                 if data[0] == 53 {
                     println!("Got expected result, ASCII code for the number 5");
                 }
             }
-            VmExit::IoOutStr(port, bytes, index, count, repeat) => {
+            VmExit::IoOutStr { port, bytes, index, count, repeat, .. } => {
                 println!("exit for IoOutStr, port={}, bytes={}, index={}, count={}, repeat={}", port, bytes, index, count, repeat);
             }
-            VmExit::Vmx(s, r, q, t, e) => {
-                println!("exit for Vmx, source={}, reason={}, qualification={:b}, inst type={}, inst error={}", s, r, q, t, e);
-                if r == 2 {
+            VmExit::Vmx { status, reason, qualification, inst_type, inst_error } => {
+                println!("exit for Vmx, source={}, reason={:?}, qualification={:b}, inst type={}, inst error={}", status, reason, qualification, inst_type, inst_error);
+                if reason == VmxExitReason::TripleFault {
                     println!("Exit reason is triple fault");
                     break;
                 }
             }
+            exit @ VmExit::Svm { .. } => {
+                let unhandled = exit.unhandled().unwrap();
+                println!("{}", unhandled);
+                if unhandled.kind == UnhandledKind::Shutdown {
+                    println!("Exit reason is shutdown");
+                    break;
+                }
+            }
             VmExit::Bogus => {
                 println!("exit for Bogus");
                 break;
             }
-            VmExit::Halt => {
-                println!("exit for Halt");
+            VmExit::Halt { intr_enabled, .. } => {
+                println!("exit for Halt, interrupts enabled={}", intr_enabled);
                 break;
             }
-            VmExit::Suspended => {
-                println!("exit for Suspended");
+            VmExit::Suspended(reason) => {
+                println!("exit for Suspended, reason={:?}", reason);
                 break;
             }
             reason => println!("Unhandled exit reason {:?}", reason)
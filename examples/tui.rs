@@ -85,7 +85,12 @@ fn cmd_stats_vm(vm_name: &str) {
     println!("Opened a filehandle to /dev/vmm/{}", vm.name);
 
     match vm.get_stats(0) {
-        Ok(entries) => println!("Got stats for VM at /dev/vmm/{}, {} entries", vm_name, entries),
+        Ok(stats) => {
+            println!("Got stats for VM at /dev/vmm/{}, {} entries", vm_name, stats.entries.len());
+            for (name, value) in stats.entries.iter() {
+                println!("    {}: {}", name, value);
+            }
+        }
         Err(e) => println!("Failed to get stats for VM at /dev/vmm/{}, with error: {}", vm_name, e),
     };
 }
@@ -0,0 +1,896 @@
+//! x86 instruction decoding for MMIO emulation.
+//!
+//! Decodes the instruction bytes reported by `VmExit::InstEmul` into a
+//! structured `Instruction`. Only the instructions that guests commonly
+//! use to access memory-mapped I/O are supported: MOV, MOVZX, MOVSX, the
+//! arithmetic and logic instructions (ADD, OR, ADC, SBB, AND, SUB, XOR,
+//! CMP), TEST, BT, STOS, MOVS, PUSH and POP.
+
+use std::error;
+use std::fmt;
+
+use crate::vm::{vm_cpu_mode, vm_reg_name};
+
+// Architectural limit on the length of an instruction.
+const MAX_INST_LENGTH: usize = 15;
+
+/// Errors returned by `decode`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    /// The instruction bytes end before the instruction does.
+    Truncated,
+    /// The instruction is longer than 15 bytes.
+    TooLong,
+    /// The opcode isn't supported. Two-byte opcodes are reported with the
+    /// 0x0F escape byte in the upper byte.
+    UnsupportedOpcode(u16),
+    /// The opcode is supported, but not with the operands it was encoded
+    /// with, such as a register operand where memory is required.
+    InvalidOperand(u16),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::Truncated => write!(f, "instruction is truncated"),
+            DecodeError::TooLong => write!(f, "instruction is longer than {} bytes", MAX_INST_LENGTH),
+            DecodeError::UnsupportedOpcode(op) => write!(f, "unsupported opcode {:#x}", op),
+            DecodeError::InvalidOperand(op) => write!(f, "invalid operand for opcode {:#x}", op),
+        }
+    }
+}
+
+impl error::Error for DecodeError {}
+
+/// Operations of decoded instructions.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Operation {
+    Mov,
+    /// Move with zero extension from 'src_size' to 'opsize'
+    Movzx,
+    /// Move with sign extension from 'src_size' to 'opsize'
+    Movsx,
+    Add,
+    Or,
+    Adc,
+    Sbb,
+    And,
+    Sub,
+    Xor,
+    Cmp,
+    Test,
+    /// Bit test, the bit offset is the immediate source operand
+    Bt,
+    Stos,
+    Movs,
+    Push,
+    Pop,
+}
+
+// Operations of the arithmetic and logic opcodes 0x00-0x3d, and of the
+// group 1 opcodes 0x80-0x83, indexed by opcode bits 5:3 or ModRM.reg.
+const ALU_OPS: [Operation; 8] = [
+    Operation::Add,
+    Operation::Or,
+    Operation::Adc,
+    Operation::Sbb,
+    Operation::And,
+    Operation::Sub,
+    Operation::Xor,
+    Operation::Cmp,
+];
+
+/// REP prefixes of decoded instructions.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RepPrefix {
+    None,
+    /// REP, REPE or REPZ (0xF3)
+    Rep,
+    /// REPNE or REPNZ (0xF2)
+    Repne,
+}
+
+/// A memory operand. The effective address is the sum of the base register,
+/// the index register times the scale, and the displacement, plus the RIP
+/// of the next instruction if the operand is RIP-relative.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MemoryOperand {
+    /// Segment register of the access, after any segment override
+    pub segment: vm_reg_name,
+    pub base: Option<vm_reg_name>,
+    pub index: Option<vm_reg_name>,
+    /// Scale of the index register: 1, 2, 4, or 8
+    pub scale: u8,
+    /// Displacement, sign extended
+    pub displacement: i64,
+    pub rip_relative: bool,
+}
+
+impl MemoryOperand {
+    /// Calculates the effective address, truncated to the address size
+    /// 'addrsize' in bytes. 'next_rip' is the RIP of the next instruction,
+    /// and 'reg' returns the value of a register.
+    pub fn effective_address<F>(&self, addrsize: u8, next_rip: u64, mut reg: F) -> u64
+        where F: FnMut(vm_reg_name) -> u64
+    {
+        let mut addr = self.displacement as u64;
+        if self.rip_relative {
+            addr = addr.wrapping_add(next_rip);
+        }
+        if let Some(base) = self.base {
+            addr = addr.wrapping_add(reg(base));
+        }
+        if let Some(index) = self.index {
+            addr = addr.wrapping_add(reg(index).wrapping_mul(self.scale as u64));
+        }
+        return addr & size_mask(addrsize);
+    }
+}
+
+/// Operands of decoded instructions.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Operand {
+    /// The low bytes of a general purpose register, as many as the operand
+    /// size
+    Register(vm_reg_name),
+    /// Bits 15:8 of RAX, RCX, RDX, or RBX (AH, CH, DH, or BH)
+    HighByte(vm_reg_name),
+    Memory(MemoryOperand),
+    /// Immediate value, sign extended
+    Immediate(i64),
+}
+
+/// A decoded instruction.
+///
+/// Instructions have a destination and a source operand, in Intel operand
+/// order. For CMP, TEST and BT the destination is only read. For STOS and
+/// MOVS the memory operands are implicit, at RDI and RSI. For PUSH the
+/// destination is the stack slot below RSP, and for POP the source is the
+/// stack slot at RSP. Their stack address size is set by the SS descriptor,
+/// not by 'addrsize'.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Instruction {
+    pub op: Operation,
+    pub dst: Operand,
+    pub src: Operand,
+    /// Operand size in bytes: 1, 2, 4, or 8
+    pub opsize: u8,
+    /// Size of the source operand in bytes, which only differs from
+    /// 'opsize' for MOVZX and MOVSX
+    pub src_size: u8,
+    /// Address size in bytes: 2, 4, or 8
+    pub addrsize: u8,
+    pub rep: RepPrefix,
+    pub lock: bool,
+    /// Length of the instruction in bytes
+    pub length: u8,
+}
+
+impl Instruction {
+    /// Returns the memory operand of the instruction, or the destination
+    /// if both operands are in memory (MOVS).
+    pub fn memory_operand(&self) -> Option<MemoryOperand> {
+        if let Operand::Memory(mem) = self.dst {
+            return Some(mem);
+        }
+        if let Operand::Memory(mem) = self.src {
+            return Some(mem);
+        }
+        return None;
+    }
+}
+
+/// Returns the mask for a value of 'size' bytes.
+pub fn size_mask(size: u8) -> u64 {
+    if size >= 8 {
+        return !0;
+    } else {
+        return (1 << (size as u64 * 8)) - 1;
+    }
+}
+
+// Reads instruction bytes and tracks the state of the decoding.
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    opcode: u16,
+    mode64: bool,
+    rex: u8,
+    segment: Option<vm_reg_name>,
+    opsize: u8,
+    addrsize: u8,
+}
+
+// ModRM fields, with the REX extensions applied.
+struct ModRm {
+    mode: u8,
+    reg: u8,
+    rm: u8,
+}
+
+const REX_W: u8 = 0x08;
+const REX_R: u8 = 0x04;
+const REX_X: u8 = 0x02;
+const REX_B: u8 = 0x01;
+
+impl<'a> Decoder<'a> {
+    fn peek(&self) -> Result<u8, DecodeError> {
+        if self.pos >= MAX_INST_LENGTH {
+            return Err(DecodeError::TooLong);
+        }
+        match self.bytes.get(self.pos) {
+            Some(byte) => return Ok(*byte),
+            None => return Err(DecodeError::Truncated),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        let byte = self.peek()?;
+        self.pos += 1;
+        return Ok(byte);
+    }
+
+    // Reads a little-endian value of 'size' bytes.
+    fn unsigned(&mut self, size: u8) -> Result<u64, DecodeError> {
+        let mut value: u64 = 0;
+        for i in 0..size {
+            value |= (self.byte()? as u64) << (i * 8);
+        }
+        return Ok(value);
+    }
+
+    // Reads a little-endian value of 'size' bytes, and sign extends it.
+    fn signed(&mut self, size: u8) -> Result<i64, DecodeError> {
+        if size == 0 {
+            return Ok(0);
+        }
+        let value = self.unsigned(size)?;
+        let shift = 64 - (size as u32 * 8);
+        return Ok(((value << shift) as i64) >> shift);
+    }
+
+    // Reads an immediate of the operand size, which is at most 32 bits.
+    fn immediate(&mut self, opsize: u8) -> Result<Operand, DecodeError> {
+        let size = if opsize == 2 { 2 } else if opsize == 1 { 1 } else { 4 };
+        return Ok(Operand::Immediate(self.signed(size)?));
+    }
+
+    fn modrm(&mut self) -> Result<ModRm, DecodeError> {
+        let byte = self.byte()?;
+        let mut modrm = ModRm {
+            mode: byte >> 6,
+            reg: (byte >> 3) & 0x7,
+            rm: byte & 0x7,
+        };
+        if (self.rex & REX_R) != 0 {
+            modrm.reg |= 0x8;
+        }
+        // REX.B extends 'rm' only if it names a register, for memory
+        // operands it extends the base register instead.
+        if modrm.mode == 3 && (self.rex & REX_B) != 0 {
+            modrm.rm |= 0x8;
+        }
+        return Ok(modrm);
+    }
+
+    // Returns the general purpose register 'index' as an operand of 'size'
+    // bytes.
+    fn register(&self, index: u8, size: u8) -> Operand {
+        if size == 1 && self.rex == 0 && index >= 4 && index < 8 {
+            return Operand::HighByte(vm_reg_name::from_gpr_index(index - 4).unwrap());
+        }
+        return Operand::Register(vm_reg_name::from_gpr_index(index).unwrap());
+    }
+
+    fn memory(&self, base: Option<vm_reg_name>, index: Option<vm_reg_name>, scale: u8,
+              displacement: i64, rip_relative: bool) -> Operand {
+        let stack = base == Some(vm_reg_name::VM_REG_GUEST_RSP) || base == Some(vm_reg_name::VM_REG_GUEST_RBP);
+        let default = if stack { vm_reg_name::VM_REG_GUEST_SS } else { vm_reg_name::VM_REG_GUEST_DS };
+        return Operand::Memory(MemoryOperand {
+            segment: self.segment.unwrap_or(default),
+            base: base,
+            index: index,
+            scale: scale,
+            displacement: displacement,
+            rip_relative: rip_relative,
+        });
+    }
+
+    // Decodes the operand named by the 'mod' and 'rm' fields of ModRM,
+    // reading any SIB byte and displacement. A register operand is 'size'
+    // bytes wide.
+    fn rm_operand(&mut self, modrm: &ModRm, size: u8) -> Result<Operand, DecodeError> {
+        if modrm.mode == 3 {
+            return Ok(self.register(modrm.rm, size));
+        }
+        if self.addrsize == 2 {
+            return self.rm_operand16(modrm);
+        }
+
+        let mut base = None;
+        let mut index = None;
+        let mut scale = 1;
+        let mut rip_relative = false;
+        let mut disp_size = match modrm.mode {
+            1 => 1,
+            2 => 4,
+            _ => 0,
+        };
+        let rex_b = if (self.rex & REX_B) != 0 { 0x8 } else { 0 };
+
+        if modrm.rm == 4 {
+            let sib = self.byte()?;
+            scale = 1 << (sib >> 6);
+            let sib_index = ((sib >> 3) & 0x7) | if (self.rex & REX_X) != 0 { 0x8 } else { 0 };
+            let sib_base = sib & 0x7;
+            // An index of RSP means no index
+            if sib_index != 4 {
+                index = vm_reg_name::from_gpr_index(sib_index);
+            }
+            // A base of RBP or R13 without a displacement means no base
+            if sib_base == 5 && modrm.mode == 0 {
+                disp_size = 4;
+            } else {
+                base = vm_reg_name::from_gpr_index(sib_base | rex_b);
+            }
+        } else if modrm.rm == 5 && modrm.mode == 0 {
+            // Absolute in 32-bit modes, RIP-relative in 64-bit mode
+            disp_size = 4;
+            rip_relative = self.mode64;
+        } else {
+            base = vm_reg_name::from_gpr_index(modrm.rm | rex_b);
+        }
+
+        let displacement = self.signed(disp_size)?;
+        return Ok(self.memory(base, index, scale, displacement, rip_relative));
+    }
+
+    // Decodes a memory operand with 16-bit addressing.
+    fn rm_operand16(&mut self, modrm: &ModRm) -> Result<Operand, DecodeError> {
+        use crate::vm::vm_reg_name::*;
+
+        let (mut base, index) = match modrm.rm {
+            0 => (Some(VM_REG_GUEST_RBX), Some(VM_REG_GUEST_RSI)),
+            1 => (Some(VM_REG_GUEST_RBX), Some(VM_REG_GUEST_RDI)),
+            2 => (Some(VM_REG_GUEST_RBP), Some(VM_REG_GUEST_RSI)),
+            3 => (Some(VM_REG_GUEST_RBP), Some(VM_REG_GUEST_RDI)),
+            4 => (Some(VM_REG_GUEST_RSI), None),
+            5 => (Some(VM_REG_GUEST_RDI), None),
+            6 => (Some(VM_REG_GUEST_RBP), None),
+            _ => (Some(VM_REG_GUEST_RBX), None),
+        };
+        let displacement = match modrm.mode {
+            0 if modrm.rm == 6 => {
+                // A base of BP without a displacement means no base
+                base = None;
+                self.signed(2)?
+            }
+            0 => 0,
+            1 => self.signed(1)?,
+            _ => self.signed(2)?,
+        };
+        return Ok(self.memory(base, index, 1, displacement, false));
+    }
+
+    // Decodes a ModRM operand that must be in memory.
+    fn mem_operand(&mut self, modrm: &ModRm, size: u8) -> Result<Operand, DecodeError> {
+        if modrm.mode == 3 {
+            return Err(DecodeError::InvalidOperand(self.opcode));
+        }
+        return self.rm_operand(modrm, size);
+    }
+}
+
+/// Decodes an instruction from 'bytes', which may extend past the end of
+/// the instruction. 'cpu_mode' and 'cs_d' (CS.D, the default operand size
+/// is 32-bit) determine the default operand and address sizes.
+pub fn decode(bytes: &[u8], cpu_mode: vm_cpu_mode, cs_d: bool) -> Result<Instruction, DecodeError> {
+    let mode64 = cpu_mode == vm_cpu_mode::CPU_MODE_64BIT;
+    let mut d = Decoder {
+        bytes: bytes,
+        pos: 0,
+        opcode: 0,
+        mode64: mode64,
+        rex: 0,
+        segment: None,
+        opsize: 0,
+        addrsize: 0,
+    };
+
+    // Legacy prefixes, followed by a REX prefix in 64-bit mode. A REX
+    // prefix is ignored unless it immediately precedes the opcode.
+    let mut opsize_override = false;
+    let mut addrsize_override = false;
+    let mut rep = RepPrefix::None;
+    let mut lock = false;
+    loop {
+        let byte = d.peek()?;
+        match byte {
+            0x66 => opsize_override = true,
+            0x67 => addrsize_override = true,
+            0xf0 => lock = true,
+            0xf2 => rep = RepPrefix::Repne,
+            0xf3 => rep = RepPrefix::Rep,
+            0x26 => d.segment = Some(vm_reg_name::VM_REG_GUEST_ES),
+            0x2e => d.segment = Some(vm_reg_name::VM_REG_GUEST_CS),
+            0x36 => d.segment = Some(vm_reg_name::VM_REG_GUEST_SS),
+            0x3e => d.segment = Some(vm_reg_name::VM_REG_GUEST_DS),
+            0x64 => d.segment = Some(vm_reg_name::VM_REG_GUEST_FS),
+            0x65 => d.segment = Some(vm_reg_name::VM_REG_GUEST_GS),
+            0x40..=0x4f if mode64 => {
+                d.pos += 1;
+                d.rex = byte;
+                continue;
+            }
+            _ => break,
+        }
+        d.pos += 1;
+        d.rex = 0;
+    }
+
+    if mode64 {
+        d.opsize = if (d.rex & REX_W) != 0 { 8 } else if opsize_override { 2 } else { 4 };
+        d.addrsize = if addrsize_override { 4 } else { 8 };
+    } else {
+        d.opsize = if cs_d != opsize_override { 4 } else { 2 };
+        d.addrsize = if cs_d != addrsize_override { 4 } else { 2 };
+    }
+    let opsize = d.opsize;
+
+    d.opcode = d.byte()? as u16;
+    if d.opcode == 0x0f {
+        d.opcode = 0x0f00 | d.byte()? as u16;
+    }
+
+    let (op, dst, src, size, src_size) = match d.opcode {
+        // Arithmetic and logic, in six forms
+        0x00..=0x3d if (d.opcode & 0x7) < 6 => {
+            let op = ALU_OPS[(d.opcode >> 3) as usize];
+            let size = if (d.opcode & 0x1) == 0 { 1 } else { opsize };
+            match d.opcode & 0x7 {
+                0 | 1 => {
+                    let modrm = d.modrm()?;
+                    let rm = d.rm_operand(&modrm, size)?;
+                    (op, rm, d.register(modrm.reg, size), size, size)
+                }
+                2 | 3 => {
+                    let modrm = d.modrm()?;
+                    let rm = d.rm_operand(&modrm, size)?;
+                    (op, d.register(modrm.reg, size), rm, size, size)
+                }
+                _ => {
+                    let imm = d.immediate(size)?;
+                    (op, Operand::Register(vm_reg_name::VM_REG_GUEST_RAX), imm, size, size)
+                }
+            }
+        }
+        // Group 1, arithmetic and logic with an immediate
+        0x80 | 0x81 | 0x83 => {
+            let modrm = d.modrm()?;
+            let size = if d.opcode == 0x80 { 1 } else { opsize };
+            let rm = d.rm_operand(&modrm, size)?;
+            let imm = if d.opcode == 0x81 { d.immediate(size)? } else { Operand::Immediate(d.signed(1)?) };
+            (ALU_OPS[(modrm.reg & 0x7) as usize], rm, imm, size, size)
+        }
+        0x84 | 0x85 => {
+            let size = if d.opcode == 0x84 { 1 } else { opsize };
+            let modrm = d.modrm()?;
+            let rm = d.rm_operand(&modrm, size)?;
+            (Operation::Test, rm, d.register(modrm.reg, size), size, size)
+        }
+        0xa8 | 0xa9 => {
+            let size = if d.opcode == 0xa8 { 1 } else { opsize };
+            let imm = d.immediate(size)?;
+            (Operation::Test, Operand::Register(vm_reg_name::VM_REG_GUEST_RAX), imm, size, size)
+        }
+        0x88..=0x8b => {
+            let size = if (d.opcode & 0x1) == 0 { 1 } else { opsize };
+            let modrm = d.modrm()?;
+            let rm = d.rm_operand(&modrm, size)?;
+            let reg = d.register(modrm.reg, size);
+            if (d.opcode & 0x2) == 0 {
+                (Operation::Mov, rm, reg, size, size)
+            } else {
+                (Operation::Mov, reg, rm, size, size)
+            }
+        }
+        // MOV between the accumulator and an absolute address
+        0xa0..=0xa3 => {
+            let size = if (d.opcode & 0x1) == 0 { 1 } else { opsize };
+            let offset = d.unsigned(d.addrsize)?;
+            let mem = d.memory(None, None, 1, offset as i64, false);
+            let rax = Operand::Register(vm_reg_name::VM_REG_GUEST_RAX);
+            if (d.opcode & 0x2) == 0 {
+                (Operation::Mov, rax, mem, size, size)
+            } else {
+                (Operation::Mov, mem, rax, size, size)
+            }
+        }
+        0xc6 | 0xc7 => {
+            let size = if d.opcode == 0xc6 { 1 } else { opsize };
+            let modrm = d.modrm()?;
+            if (modrm.reg & 0x7) != 0 {
+                return Err(DecodeError::UnsupportedOpcode(d.opcode));
+            }
+            let rm = d.rm_operand(&modrm, size)?;
+            (Operation::Mov, rm, d.immediate(size)?, size, size)
+        }
+        0xf6 | 0xf7 => {
+            let size = if d.opcode == 0xf6 { 1 } else { opsize };
+            let modrm = d.modrm()?;
+            if (modrm.reg & 0x7) != 0 {
+                return Err(DecodeError::UnsupportedOpcode(d.opcode));
+            }
+            let rm = d.rm_operand(&modrm, size)?;
+            (Operation::Test, rm, d.immediate(size)?, size, size)
+        }
+        0x0fb6 | 0x0fb7 | 0x0fbe | 0x0fbf => {
+            let op = if d.opcode < 0x0fbe { Operation::Movzx } else { Operation::Movsx };
+            let src_size = if (d.opcode & 0x1) == 0 { 1 } else { 2 };
+            let modrm = d.modrm()?;
+            let rm = d.rm_operand(&modrm, src_size)?;
+            (op, d.register(modrm.reg, opsize), rm, opsize, src_size)
+        }
+        0x0fba => {
+            let modrm = d.modrm()?;
+            if (modrm.reg & 0x7) != 4 {
+                return Err(DecodeError::UnsupportedOpcode(d.opcode));
+            }
+            let rm = d.rm_operand(&modrm, opsize)?;
+            (Operation::Bt, rm, Operand::Immediate(d.unsigned(1)? as i64), opsize, 1)
+        }
+        0xa4 | 0xa5 | 0xaa | 0xab => {
+            let size = if (d.opcode & 0x1) == 0 { 1 } else { opsize };
+            // The destination segment is always ES
+            let dst = Operand::Memory(MemoryOperand {
+                segment: vm_reg_name::VM_REG_GUEST_ES,
+                base: Some(vm_reg_name::VM_REG_GUEST_RDI),
+                index: None,
+                scale: 1,
+                displacement: 0,
+                rip_relative: false,
+            });
+            if d.opcode < 0xaa {
+                let src = d.memory(Some(vm_reg_name::VM_REG_GUEST_RSI), None, 1, 0, false);
+                (Operation::Movs, dst, src, size, size)
+            } else {
+                (Operation::Stos, dst, Operand::Register(vm_reg_name::VM_REG_GUEST_RAX), size, size)
+            }
+        }
+        0x8f | 0xff => {
+            let modrm = d.modrm()?;
+            let expected = if d.opcode == 0x8f { 0 } else { 6 };
+            if (modrm.reg & 0x7) != expected {
+                return Err(DecodeError::UnsupportedOpcode(d.opcode));
+            }
+            // Stack operations are 64-bit in 64-bit mode, unless overridden
+            let size = if mode64 && !opsize_override { 8 } else { opsize };
+            let rm = d.mem_operand(&modrm, size)?;
+            let stack = MemoryOperand {
+                segment: vm_reg_name::VM_REG_GUEST_SS,
+                base: Some(vm_reg_name::VM_REG_GUEST_RSP),
+                index: None,
+                scale: 1,
+                displacement: if d.opcode == 0xff { -(size as i64) } else { 0 },
+                rip_relative: false,
+            };
+            if d.opcode == 0xff {
+                (Operation::Push, Operand::Memory(stack), rm, size, size)
+            } else {
+                (Operation::Pop, rm, Operand::Memory(stack), size, size)
+            }
+        }
+        opcode => return Err(DecodeError::UnsupportedOpcode(opcode)),
+    };
+
+    return Ok(Instruction {
+        op: op,
+        dst: dst,
+        src: src,
+        opsize: size,
+        src_size: src_size,
+        addrsize: d.addrsize,
+        rep: rep,
+        lock: lock,
+        length: d.pos as u8,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decode::*;
+    use crate::vm::vm_reg_name::*;
+    use crate::vm::vm_cpu_mode::*;
+
+    fn decode64(bytes: &[u8]) -> Instruction {
+        decode(bytes, CPU_MODE_64BIT, false).unwrap()
+    }
+
+    fn mem(segment: vm_reg_name, base: Option<vm_reg_name>, index: Option<vm_reg_name>,
+           scale: u8, displacement: i64) -> Operand {
+        Operand::Memory(MemoryOperand {
+            segment: segment,
+            base: base,
+            index: index,
+            scale: scale,
+            displacement: displacement,
+            rip_relative: false,
+        })
+    }
+
+    #[test]
+    fn test_mov() {
+        // mov %eax, (%rbx)
+        let inst = decode64(&[0x89, 0x03]);
+        assert_eq!(inst.op, Operation::Mov);
+        assert_eq!(inst.dst, mem(VM_REG_GUEST_DS, Some(VM_REG_GUEST_RBX), None, 1, 0));
+        assert_eq!(inst.src, Operand::Register(VM_REG_GUEST_RAX));
+        assert_eq!(inst.opsize, 4);
+        assert_eq!(inst.addrsize, 8);
+        assert_eq!(inst.length, 2);
+
+        // mov 0x10(%r12), %r9
+        let inst = decode64(&[0x4d, 0x8b, 0x4c, 0x24, 0x10]);
+        assert_eq!(inst.dst, Operand::Register(VM_REG_GUEST_R9));
+        assert_eq!(inst.src, mem(VM_REG_GUEST_DS, Some(VM_REG_GUEST_R12), None, 1, 0x10));
+        assert_eq!(inst.opsize, 8);
+        assert_eq!(inst.length, 5);
+
+        // mov %ax, -0x4(%rbp)
+        let inst = decode64(&[0x66, 0x89, 0x45, 0xfc]);
+        assert_eq!(inst.dst, mem(VM_REG_GUEST_SS, Some(VM_REG_GUEST_RBP), None, 1, -4));
+        assert_eq!(inst.opsize, 2);
+
+        // mov %ah, (%rdi) and mov %spl, (%rdi)
+        let inst = decode64(&[0x88, 0x27]);
+        assert_eq!(inst.src, Operand::HighByte(VM_REG_GUEST_RAX));
+        assert_eq!(inst.opsize, 1);
+        let inst = decode64(&[0x40, 0x88, 0x27]);
+        assert_eq!(inst.src, Operand::Register(VM_REG_GUEST_RSP));
+
+        // movl $0x12345678, 0xfee000b0
+        let inst = decode64(&[0xc7, 0x04, 0x25, 0xb0, 0x00, 0xe0, 0xfe, 0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(inst.dst, mem(VM_REG_GUEST_DS, None, None, 1, 0xfffffffffee000b0u64 as i64));
+        assert_eq!(inst.src, Operand::Immediate(0x12345678));
+        assert_eq!(inst.length, 11);
+
+        // movq $-1, (%rax)
+        let inst = decode64(&[0x48, 0xc7, 0x00, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(inst.src, Operand::Immediate(-1));
+        assert_eq!(inst.opsize, 8);
+
+        // mov 0xfee00020, %eax (moffs)
+        let inst = decode64(&[0xa1, 0x20, 0x00, 0xe0, 0xfe, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(inst.dst, Operand::Register(VM_REG_GUEST_RAX));
+        assert_eq!(inst.src, mem(VM_REG_GUEST_DS, None, None, 1, 0xfee00020));
+        assert_eq!(inst.length, 9);
+
+        // mov %al, %fs:0x10 (moffs, 32-bit)
+        let inst = decode(&[0x64, 0xa2, 0x10, 0x00, 0x00, 0x00], CPU_MODE_PROTECTED, true).unwrap();
+        assert_eq!(inst.dst, mem(VM_REG_GUEST_FS, None, None, 1, 0x10));
+        assert_eq!(inst.opsize, 1);
+        assert_eq!(inst.length, 6);
+    }
+
+    #[test]
+    fn test_addressing() {
+        // mov (%rcx,%rdx,4), %eax
+        let inst = decode64(&[0x8b, 0x04, 0x91]);
+        assert_eq!(inst.src, mem(VM_REG_GUEST_DS, Some(VM_REG_GUEST_RCX), Some(VM_REG_GUEST_RDX), 4, 0));
+
+        // mov 0x100(,%r11,8), %eax
+        let inst = decode64(&[0x42, 0x8b, 0x04, 0xdd, 0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(inst.src, mem(VM_REG_GUEST_DS, None, Some(VM_REG_GUEST_R11), 8, 0x100));
+
+        // mov (%r13), %eax needs a displacement of 0
+        let inst = decode64(&[0x41, 0x8b, 0x45, 0x00]);
+        assert_eq!(inst.src, mem(VM_REG_GUEST_DS, Some(VM_REG_GUEST_R13), None, 1, 0));
+
+        // mov 0x1000(%rip), %eax
+        let inst = decode64(&[0x8b, 0x05, 0x00, 0x10, 0x00, 0x00]);
+        let operand = inst.memory_operand().unwrap();
+        assert!(operand.rip_relative);
+        assert_eq!(operand.base, None);
+        assert_eq!(operand.effective_address(inst.addrsize, 0x2006, |_| 0), 0x3006);
+
+        // mov 0x1000, %eax in protected mode is absolute
+        let inst = decode(&[0x8b, 0x05, 0x00, 0x10, 0x00, 0x00], CPU_MODE_PROTECTED, true).unwrap();
+        let operand = inst.memory_operand().unwrap();
+        assert!(!operand.rip_relative);
+        assert_eq!(operand.effective_address(inst.addrsize, 0x2006, |_| 0), 0x1000);
+
+        // mov (%esp), %eax with an address size override
+        let inst = decode64(&[0x67, 0x8b, 0x04, 0x24]);
+        assert_eq!(inst.addrsize, 4);
+        let operand = inst.memory_operand().unwrap();
+        assert_eq!(operand.segment, VM_REG_GUEST_SS);
+        assert_eq!(operand.effective_address(inst.addrsize, 0, |_| 0x1_0000_fff0), 0xfff0);
+    }
+
+    #[test]
+    fn test_addressing16() {
+        // mov %ax, 0x10(%bp,%si) in real mode
+        let inst = decode(&[0x89, 0x42, 0x10], CPU_MODE_REAL, false).unwrap();
+        assert_eq!(inst.dst, mem(VM_REG_GUEST_SS, Some(VM_REG_GUEST_RBP), Some(VM_REG_GUEST_RSI), 1, 0x10));
+        assert_eq!(inst.opsize, 2);
+        assert_eq!(inst.addrsize, 2);
+
+        // mov %al, 0xb800
+        let inst = decode(&[0x88, 0x06, 0x00, 0xb8], CPU_MODE_REAL, false).unwrap();
+        assert_eq!(inst.dst, mem(VM_REG_GUEST_DS, None, None, 1, -0x4800));
+        let operand = inst.memory_operand().unwrap();
+        assert_eq!(operand.effective_address(inst.addrsize, 0, |_| 0), 0xb800);
+
+        // mov %eax, (%di) with an operand size override
+        let inst = decode(&[0x66, 0x89, 0x05], CPU_MODE_REAL, false).unwrap();
+        assert_eq!(inst.dst, mem(VM_REG_GUEST_DS, Some(VM_REG_GUEST_RDI), None, 1, 0));
+        assert_eq!(inst.opsize, 4);
+    }
+
+    #[test]
+    fn test_alu() {
+        // and %ecx, (%rax)
+        let inst = decode64(&[0x21, 0x08]);
+        assert_eq!(inst.op, Operation::And);
+        assert_eq!(inst.src, Operand::Register(VM_REG_GUEST_RCX));
+
+        // or (%rax), %dl
+        let inst = decode64(&[0x0a, 0x10]);
+        assert_eq!(inst.op, Operation::Or);
+        assert_eq!(inst.dst, Operand::Register(VM_REG_GUEST_RDX));
+        assert_eq!(inst.opsize, 1);
+
+        // sub 0x8(%rbx), %rax
+        let inst = decode64(&[0x48, 0x2b, 0x43, 0x08]);
+        assert_eq!(inst.op, Operation::Sub);
+        assert_eq!(inst.opsize, 8);
+
+        // cmp $0x5, %al
+        let inst = decode64(&[0x3c, 0x05]);
+        assert_eq!(inst.op, Operation::Cmp);
+        assert_eq!(inst.src, Operand::Immediate(5));
+
+        // andl $0xfffffffe, (%rdi)
+        let inst = decode64(&[0x83, 0x27, 0xfe]);
+        assert_eq!(inst.op, Operation::And);
+        assert_eq!(inst.src, Operand::Immediate(-2));
+        assert_eq!(inst.opsize, 4);
+
+        // orl $0x100, 0x4(%rdi)
+        let inst = decode64(&[0x81, 0x4f, 0x04, 0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(inst.op, Operation::Or);
+        assert_eq!(inst.src, Operand::Immediate(0x100));
+        assert_eq!(inst.length, 7);
+
+        // orw $0x100, (%rdi)
+        let inst = decode64(&[0x66, 0x81, 0x0f, 0x00, 0x01]);
+        assert_eq!(inst.src, Operand::Immediate(0x100));
+        assert_eq!(inst.opsize, 2);
+        assert_eq!(inst.length, 5);
+
+        // cmpb $0x80, (%rsi)
+        let inst = decode64(&[0x80, 0x3e, 0x80]);
+        assert_eq!(inst.op, Operation::Cmp);
+        assert_eq!(inst.src, Operand::Immediate(-0x80));
+        assert_eq!(inst.opsize, 1);
+
+        // lock xor %eax, (%rdx)
+        let inst = decode64(&[0xf0, 0x31, 0x02]);
+        assert_eq!(inst.op, Operation::Xor);
+        assert!(inst.lock);
+    }
+
+    #[test]
+    fn test_test_and_bt() {
+        // test %ecx, (%rax)
+        let inst = decode64(&[0x85, 0x08]);
+        assert_eq!(inst.op, Operation::Test);
+
+        // testb $0x1, (%rax)
+        let inst = decode64(&[0xf6, 0x00, 0x01]);
+        assert_eq!(inst.op, Operation::Test);
+        assert_eq!(inst.src, Operand::Immediate(1));
+        assert_eq!(inst.opsize, 1);
+
+        // testl $0x80000000, (%rax)
+        let inst = decode64(&[0xf7, 0x00, 0x00, 0x00, 0x00, 0x80]);
+        assert_eq!(inst.src, Operand::Immediate(-0x80000000));
+        assert_eq!(inst.length, 6);
+
+        // btl $0x1f, (%rbx)
+        let inst = decode64(&[0x0f, 0xba, 0x23, 0x1f]);
+        assert_eq!(inst.op, Operation::Bt);
+        assert_eq!(inst.src, Operand::Immediate(0x1f));
+        assert_eq!(inst.length, 4);
+
+        // btr is not supported
+        assert_eq!(decode(&[0x0f, 0xba, 0x33, 0x1f], CPU_MODE_64BIT, false),
+                   Err(DecodeError::UnsupportedOpcode(0x0fba)));
+    }
+
+    #[test]
+    fn test_movzx_movsx() {
+        // movzbl (%rax), %ecx
+        let inst = decode64(&[0x0f, 0xb6, 0x08]);
+        assert_eq!(inst.op, Operation::Movzx);
+        assert_eq!(inst.dst, Operand::Register(VM_REG_GUEST_RCX));
+        assert_eq!(inst.opsize, 4);
+        assert_eq!(inst.src_size, 1);
+
+        // movswq (%rax), %r8
+        let inst = decode64(&[0x4c, 0x0f, 0xbf, 0x00]);
+        assert_eq!(inst.op, Operation::Movsx);
+        assert_eq!(inst.dst, Operand::Register(VM_REG_GUEST_R8));
+        assert_eq!(inst.opsize, 8);
+        assert_eq!(inst.src_size, 2);
+    }
+
+    #[test]
+    fn test_string() {
+        // rep stosl
+        let inst = decode64(&[0xf3, 0xab]);
+        assert_eq!(inst.op, Operation::Stos);
+        assert_eq!(inst.rep, RepPrefix::Rep);
+        assert_eq!(inst.dst, mem(VM_REG_GUEST_ES, Some(VM_REG_GUEST_RDI), None, 1, 0));
+        assert_eq!(inst.src, Operand::Register(VM_REG_GUEST_RAX));
+        assert_eq!(inst.opsize, 4);
+
+        // rep movsb %fs:(%rsi), %es:(%rdi)
+        let inst = decode64(&[0xf3, 0x64, 0xa4]);
+        assert_eq!(inst.op, Operation::Movs);
+        assert_eq!(inst.src, mem(VM_REG_GUEST_FS, Some(VM_REG_GUEST_RSI), None, 1, 0));
+        assert_eq!(inst.dst, mem(VM_REG_GUEST_ES, Some(VM_REG_GUEST_RDI), None, 1, 0));
+        assert_eq!(inst.opsize, 1);
+
+        // movsq
+        let inst = decode64(&[0x48, 0xa5]);
+        assert_eq!(inst.opsize, 8);
+        assert_eq!(inst.rep, RepPrefix::None);
+    }
+
+    #[test]
+    fn test_push_pop() {
+        // pushq 0x8(%rax)
+        let inst = decode64(&[0xff, 0x70, 0x08]);
+        assert_eq!(inst.op, Operation::Push);
+        assert_eq!(inst.src, mem(VM_REG_GUEST_DS, Some(VM_REG_GUEST_RAX), None, 1, 8));
+        assert_eq!(inst.dst, mem(VM_REG_GUEST_SS, Some(VM_REG_GUEST_RSP), None, 1, -8));
+        assert_eq!(inst.opsize, 8);
+
+        // popl (%eax) in protected mode
+        let inst = decode(&[0x8f, 0x00], CPU_MODE_PROTECTED, true).unwrap();
+        assert_eq!(inst.op, Operation::Pop);
+        assert_eq!(inst.src, mem(VM_REG_GUEST_SS, Some(VM_REG_GUEST_RSP), None, 1, 0));
+        assert_eq!(inst.opsize, 4);
+
+        // pop %rax is not a memory operation
+        assert_eq!(decode(&[0x8f, 0xc0], CPU_MODE_64BIT, false), Err(DecodeError::InvalidOperand(0x8f)));
+    }
+
+    #[test]
+    fn test_prefixes() {
+        // A REX prefix followed by a legacy prefix is ignored
+        let inst = decode64(&[0x48, 0x66, 0x89, 0x03]);
+        assert_eq!(inst.opsize, 2);
+
+        // REX.W takes precedence over the operand size override
+        let inst = decode64(&[0x66, 0x48, 0x89, 0x03]);
+        assert_eq!(inst.opsize, 8);
+
+        // 0x40-0x4f are INC and DEC outside of 64-bit mode
+        assert_eq!(decode(&[0x48, 0x89, 0x03], CPU_MODE_PROTECTED, true), Err(DecodeError::UnsupportedOpcode(0x48)));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(decode(&[], CPU_MODE_64BIT, false), Err(DecodeError::Truncated));
+        assert_eq!(decode(&[0x89], CPU_MODE_64BIT, false), Err(DecodeError::Truncated));
+        assert_eq!(decode(&[0xc7, 0x00, 0x01, 0x02], CPU_MODE_64BIT, false), Err(DecodeError::Truncated));
+        assert_eq!(decode(&[0x0f, 0x0b], CPU_MODE_64BIT, false), Err(DecodeError::UnsupportedOpcode(0x0f0b)));
+        assert_eq!(decode(&[0x66; 16], CPU_MODE_64BIT, false), Err(DecodeError::TooLong));
+
+        // Trailing bytes after the instruction are ignored
+        let inst = decode64(&[0x89, 0x03, 0x90, 0x90]);
+        assert_eq!(inst.length, 2);
+    }
+}
@@ -0,0 +1,1103 @@
+//! MMIO instruction emulation.
+//!
+//! Executes instructions decoded by `decode` that access memory-mapped I/O,
+//! as reported by `VmExit::InstEmul`. The memory accesses go through the
+//! `MmioAccess` trait, and the register accesses through the
+//! `RegisterAccess` trait, which is implemented for a VCPU of a
+//! `VirtualMachine` by `VcpuRegisters`.
+//!
+//! Also emulates the string port I/O instructions reported by
+//! `VmExit::IoInStr` and `VmExit::IoOutStr`, moving data between guest
+//! memory and a `PortHandler`.
+
+use std::error;
+use std::fmt;
+
+use libc::{EINVAL, PROT_READ, PROT_WRITE};
+
+use crate::include::psl::{PSL_C, PSL_PF, PSL_AF, PSL_Z, PSL_N, PSL_D, PSL_V};
+use crate::decode::{decode, size_mask, DecodeError, Instruction, MemoryOperand, Operand, Operation, RepPrefix};
+use crate::segment::SegmentDescriptor;
+use crate::vm::{vm_cpu_mode, vm_guest_paging, vm_reg_name, seg_desc, FaultType, GuestMapping, Translation,
+                VirtualMachine, VmExit, VmExitInfo};
+use crate::Error;
+
+// Bits of RFLAGS that arithmetic and logic instructions update.
+const PSL_STATUS: u64 = PSL_C | PSL_PF | PSL_AF | PSL_Z | PSL_N | PSL_V;
+
+/// Errors returned by `emulate` and `emulate_inst_emul`.
+#[derive(Debug)]
+pub enum EmulateError {
+    /// The instruction bytes couldn't be decoded.
+    Decode(DecodeError),
+    /// The kernel didn't fetch the instruction bytes.
+    NoInstruction,
+    /// The instruction doesn't access memory, so it can't have caused the
+    /// exit.
+    NoMemoryOperand,
+    /// A guest linear address couldn't be computed or translated. The fault
+    /// has been injected into the VCPU, which should be resumed without
+    /// changes.
+    Fault,
+    /// Accessing a register or memory failed.
+    Sys(Error),
+}
+
+impl fmt::Display for EmulateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmulateError::Decode(e) => write!(f, "failed to decode instruction: {}", e),
+            EmulateError::NoInstruction => write!(f, "instruction bytes weren't fetched"),
+            EmulateError::NoMemoryOperand => write!(f, "instruction has no memory operand"),
+            EmulateError::Fault => write!(f, "guest memory access faulted"),
+            EmulateError::Sys(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for EmulateError {}
+
+impl From<DecodeError> for EmulateError {
+    fn from(e: DecodeError) -> EmulateError {
+        EmulateError::Decode(e)
+    }
+}
+
+impl From<Error> for EmulateError {
+    fn from(e: Error) -> EmulateError {
+        EmulateError::Sys(e)
+    }
+}
+
+/// Memory accesses of emulated instructions.
+///
+/// For MOVS, PUSH and POP, one of the two accesses is to the stack or to
+/// the other string operand, which is usually ordinary guest memory rather
+/// than MMIO.
+pub trait MmioAccess {
+    /// Reads 'size' bytes (1, 2, 4, or 8) at the guest physical address
+    /// 'gpa'.
+    fn read(&mut self, gpa: u64, size: u8) -> Result<u64, Error>;
+
+    /// Writes the low 'size' bytes (1, 2, 4, or 8) of 'value' at the guest
+    /// physical address 'gpa'.
+    fn write(&mut self, gpa: u64, size: u8, value: u64) -> Result<(), Error>;
+}
+
+/// Register accesses of emulated instructions.
+pub trait RegisterAccess {
+    fn get_register(&mut self, reg: vm_reg_name) -> Result<u64, Error>;
+
+    fn set_register(&mut self, reg: vm_reg_name, value: u64) -> Result<(), Error>;
+
+    /// Returns the base, limit and access rights of a segment register. The
+    /// default is a flat 4GB read/write data segment.
+    fn get_desc(&mut self, _reg: vm_reg_name) -> Result<(u64, u32, u32), Error> {
+        let flat = SegmentDescriptor {
+            limit: 0xffffffff,
+            default_big: true,
+            granularity: true,
+            ..SegmentDescriptor::real_mode(0)
+        };
+        Ok((flat.base, flat.limit, flat.access()))
+    }
+
+    /// Translates a guest linear address to a guest physical address, for
+    /// a write if 'write' is set. The default is an identity mapping, as
+    /// with paging disabled.
+    fn translate(&mut self, gla: u64, _write: bool) -> Result<Translation, Error> {
+        Ok(Translation::Gpa(gla))
+    }
+
+    /// Injects the exception 'vector' with the error code 'errcode', and
+    /// restarts the current instruction. The default fails with EINVAL.
+    fn inject_exception(&mut self, _vector: i32, _errcode: u32) -> Result<(), Error> {
+        Err(Error::new(EINVAL))
+    }
+}
+
+/// The registers of a VCPU of a `VirtualMachine`.
+pub struct VcpuRegisters<'a> {
+    vm: &'a VirtualMachine,
+    vcpu_id: i32,
+    paging: vm_guest_paging,
+}
+
+impl<'a> VcpuRegisters<'a> {
+    /// Accesses the registers of VCPU 'vcpu_id', translating linear
+    /// addresses with the guest paging state 'paging'.
+    pub fn new(vm: &'a VirtualMachine, vcpu_id: i32, paging: vm_guest_paging) -> VcpuRegisters<'a> {
+        VcpuRegisters {
+            vm: vm,
+            vcpu_id: vcpu_id,
+            paging: paging,
+        }
+    }
+}
+
+impl<'a> RegisterAccess for VcpuRegisters<'a> {
+    fn get_register(&mut self, reg: vm_reg_name) -> Result<u64, Error> {
+        self.vm.get_register(self.vcpu_id, reg)
+    }
+
+    fn set_register(&mut self, reg: vm_reg_name, value: u64) -> Result<(), Error> {
+        self.vm.set_register(self.vcpu_id, reg, value)?;
+        Ok(())
+    }
+
+    fn get_desc(&mut self, reg: vm_reg_name) -> Result<(u64, u32, u32), Error> {
+        self.vm.get_desc(self.vcpu_id, reg)
+    }
+
+    fn translate(&mut self, gla: u64, write: bool) -> Result<Translation, Error> {
+        let prot = if write { PROT_WRITE } else { PROT_READ };
+        self.vm.gla2gpa(self.vcpu_id, &self.paging, gla, prot)
+    }
+
+    fn inject_exception(&mut self, vector: i32, errcode: u32) -> Result<(), Error> {
+        self.vm.inject_exception(self.vcpu_id, vector, 1, errcode, 1)?;
+        Ok(())
+    }
+}
+
+/// Emulates the instruction of a `VmExit::InstEmul` exit on VCPU 'vcpu_id',
+/// and advances RIP past it.
+pub fn emulate_inst_emul(vm: &VirtualMachine, vcpu_id: i32, exit: &VmExitInfo,
+                         mmio: &mut dyn MmioAccess) -> Result<(), EmulateError> {
+    match exit.reason {
+        VmExit::InstEmul { gpa, cs_d, paging, ref inst, .. } => {
+            if inst.is_empty() {
+                return Err(EmulateError::NoInstruction);
+            }
+            let decoded = decode(inst, paging.cpu_mode, cs_d)?;
+            let mut regs = VcpuRegisters::new(vm, vcpu_id, paging);
+            return emulate(&decoded, gpa, exit.rip, paging.cpu_mode, &mut regs, mmio);
+        }
+        _ => return Err(EmulateError::Sys(Error::new(EINVAL))),
+    }
+}
+
+/// Emulates the instruction 'inst' at 'rip', whose memory operand is at the
+/// guest physical address 'gpa', in the CPU mode 'cpu_mode'. On success,
+/// RIP is advanced past the instruction, except when a REP prefixed string
+/// instruction has iterations left, in which case it will be restarted.
+pub fn emulate(inst: &Instruction, gpa: u64, rip: u64, cpu_mode: vm_cpu_mode,
+               regs: &mut dyn RegisterAccess, mmio: &mut dyn MmioAccess) -> Result<(), EmulateError> {
+    if inst.memory_operand().is_none() {
+        return Err(EmulateError::NoMemoryOperand);
+    }
+    let size = inst.opsize;
+    let mut next_rip = rip + inst.length as u64;
+
+    match inst.op {
+        Operation::Mov => {
+            let value = read_operand(&inst.src, size, gpa, regs, mmio)?;
+            write_operand(&inst.dst, size, gpa, value, regs, mmio)?;
+        }
+        Operation::Movzx | Operation::Movsx => {
+            let mut value = read_operand(&inst.src, inst.src_size, gpa, regs, mmio)?;
+            if inst.op == Operation::Movsx {
+                value = sign_extend(value, inst.src_size);
+            }
+            write_operand(&inst.dst, size, gpa, value, regs, mmio)?;
+        }
+        Operation::Add | Operation::Or | Operation::Adc | Operation::Sbb |
+        Operation::And | Operation::Sub | Operation::Xor | Operation::Cmp | Operation::Test => {
+            let dst = read_operand(&inst.dst, size, gpa, regs, mmio)?;
+            let src = read_operand(&inst.src, size, gpa, regs, mmio)?;
+            let rflags = regs.get_register(vm_reg_name::VM_REG_GUEST_RFLAGS)?;
+            let (result, flags) = alu(inst.op, dst, src, (rflags & PSL_C) != 0, size);
+            if inst.op != Operation::Cmp && inst.op != Operation::Test {
+                write_operand(&inst.dst, size, gpa, result, regs, mmio)?;
+            }
+            regs.set_register(vm_reg_name::VM_REG_GUEST_RFLAGS, (rflags & !PSL_STATUS) | flags)?;
+        }
+        Operation::Bt => {
+            let value = read_operand(&inst.dst, size, gpa, regs, mmio)?;
+            let bit = read_operand(&inst.src, 1, gpa, regs, mmio)? & (size as u64 * 8 - 1);
+            let rflags = regs.get_register(vm_reg_name::VM_REG_GUEST_RFLAGS)?;
+            let carry = if (value >> bit) & 1 != 0 { PSL_C } else { 0 };
+            regs.set_register(vm_reg_name::VM_REG_GUEST_RFLAGS, (rflags & !PSL_C) | carry)?;
+        }
+        Operation::Stos | Operation::Movs => {
+            if inst.rep != RepPrefix::None {
+                let mask = size_mask(inst.addrsize);
+                let count = regs.get_register(vm_reg_name::VM_REG_GUEST_RCX)? & mask;
+                if count == 0 {
+                    regs.set_register(vm_reg_name::VM_REG_GUEST_RIP, next_rip)?;
+                    return Ok(());
+                }
+            }
+
+            let value = if inst.op == Operation::Stos {
+                read_operand(&inst.src, size, gpa, regs, mmio)?
+            } else {
+                let src = memory_gpa(&inst.src, size, inst.addrsize, false, cpu_mode, next_rip, regs)?;
+                mmio.read(src, size)?
+            };
+            let dst = memory_gpa(&inst.dst, size, inst.addrsize, true, cpu_mode, next_rip, regs)?;
+            mmio.write(dst, size, value)?;
+
+            let rflags = regs.get_register(vm_reg_name::VM_REG_GUEST_RFLAGS)?;
+            let step = if (rflags & PSL_D) != 0 { (size as u64).wrapping_neg() } else { size as u64 };
+            add_register(regs, vm_reg_name::VM_REG_GUEST_RDI, step, inst.addrsize)?;
+            if inst.op == Operation::Movs {
+                add_register(regs, vm_reg_name::VM_REG_GUEST_RSI, step, inst.addrsize)?;
+            }
+            if inst.rep != RepPrefix::None {
+                let count = add_register(regs, vm_reg_name::VM_REG_GUEST_RCX, !0, inst.addrsize)?;
+                // Restart the instruction for the next iteration
+                if count != 0 {
+                    next_rip = rip;
+                }
+            }
+        }
+        Operation::Push | Operation::Pop => {
+            let stack_size = stack_addrsize(cpu_mode, regs)?;
+            if inst.op == Operation::Push {
+                let value = read_operand(&inst.src, size, gpa, regs, mmio)?;
+                let dst = memory_gpa(&inst.dst, size, stack_size, true, cpu_mode, next_rip, regs)?;
+                mmio.write(dst, size, value)?;
+                add_register(regs, vm_reg_name::VM_REG_GUEST_RSP, (size as u64).wrapping_neg(), stack_size)?;
+            } else {
+                let src = memory_gpa(&inst.src, size, stack_size, false, cpu_mode, next_rip, regs)?;
+                let value = mmio.read(src, size)?;
+                write_operand(&inst.dst, size, gpa, value, regs, mmio)?;
+                add_register(regs, vm_reg_name::VM_REG_GUEST_RSP, size as u64, stack_size)?;
+            }
+        }
+    }
+
+    regs.set_register(vm_reg_name::VM_REG_GUEST_RIP, next_rip)?;
+    return Ok(());
+}
+
+/// Port I/O of emulated string instructions.
+pub trait PortHandler {
+    /// Reads 'bytes' bytes (1, 2, or 4) from 'port'.
+    fn port_in(&mut self, port: u16, bytes: u8) -> Result<u32, Error>;
+
+    /// Writes the low 'bytes' bytes (1, 2, or 4) of 'value' to 'port'.
+    fn port_out(&mut self, port: u16, bytes: u8, value: u32) -> Result<(), Error>;
+}
+
+/// The guest physical memory of a `VirtualMachine`. The pages accessed stay
+/// mapped until the `GuestRam` is dropped, so it should only be kept for a
+/// batch of accesses, such as those of one exit.
+pub struct GuestRam<'a> {
+    vm: &'a VirtualMachine,
+    mappings: Vec<GuestMapping>,
+}
+
+impl<'a> GuestRam<'a> {
+    pub fn new(vm: &'a VirtualMachine) -> GuestRam<'a> {
+        GuestRam {
+            vm: vm,
+            mappings: Vec::new(),
+        }
+    }
+
+    // Returns a mapping of the 'len' bytes at 'gpa', mapping them if no
+    // earlier access already did.
+    fn mapping(&mut self, gpa: u64, len: usize, prot: i32) -> Result<&GuestMapping, Error> {
+        let found = self.mappings.iter().position(|m| m.contains(gpa, len, prot));
+        let index = match found {
+            Some(index) => index,
+            None => {
+                self.mappings.push(self.vm.map_guest(gpa, len, prot)?);
+                self.mappings.len() - 1
+            }
+        };
+        return Ok(&self.mappings[index]);
+    }
+}
+
+impl<'a> MmioAccess for GuestRam<'a> {
+    fn read(&mut self, gpa: u64, size: u8) -> Result<u64, Error> {
+        let mut buf = [0u8; 8];
+        self.mapping(gpa, size as usize, PROT_READ)?.read(gpa, &mut buf[..size as usize])?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn write(&mut self, gpa: u64, size: u8, value: u64) -> Result<(), Error> {
+        self.mapping(gpa, size as usize, PROT_WRITE)?.write(gpa, &value.to_le_bytes()[..size as usize])?;
+        Ok(())
+    }
+}
+
+/// A string port I/O instruction (INS or OUTS), from `VmExit::IoInStr` or
+/// `VmExit::IoOutStr`.
+#[derive(Debug, Copy, Clone)]
+pub struct StringIo {
+    pub port: u16,
+    /// Size of each access in bytes: 1, 2, or 4
+    pub bytes: u8,
+    /// INS rather than OUTS
+    pub is_in: bool,
+    pub repeat: bool,
+    /// Address size in bytes: 2, 4, or 8
+    pub addrsize: u8,
+    /// Offset of the buffer in its segment
+    pub index: u64,
+    /// Iterations left, 1 without a REP prefix
+    pub count: u64,
+    pub rflags: u64,
+    /// Segment register of the buffer
+    pub segment: vm_reg_name,
+    /// Descriptor of 'segment'
+    pub seg_desc: seg_desc,
+    pub cpu_mode: vm_cpu_mode,
+}
+
+// The number of string I/O iterations to emulate before returning to the
+// guest, so that a long REP sequence doesn't delay interrupts.
+const STRING_IO_BATCH: u64 = 16;
+
+/// Emulates the string port I/O instruction of a `VmExit::IoInStr` or
+/// `VmExit::IoOutStr` exit on VCPU 'vcpu_id', moving data between the
+/// guest memory and 'ports'. Updates RSI or RDI and RCX, and restarts the
+/// instruction if a REP prefixed instruction has iterations left.
+pub fn emulate_string_io(vm: &VirtualMachine, vcpu_id: i32, exit: &VmExitInfo,
+                         ports: &mut dyn PortHandler) -> Result<(), EmulateError> {
+    let (io, paging) = match exit.reason {
+        VmExit::IoInStr { port, bytes, index, count, repeat, addrsize, rflags, segment, seg_desc, paging } |
+        VmExit::IoOutStr { port, bytes, index, count, repeat, addrsize, rflags, segment, seg_desc, paging } => {
+            let io = StringIo {
+                port: port,
+                bytes: bytes as u8,
+                is_in: matches!(exit.reason, VmExit::IoInStr { .. }),
+                repeat: repeat,
+                addrsize: addrsize,
+                index: index,
+                count: count,
+                rflags: rflags,
+                segment: segment,
+                seg_desc: seg_desc,
+                cpu_mode: paging.cpu_mode,
+            };
+            (io, paging)
+        }
+        _ => return Err(EmulateError::Sys(Error::new(EINVAL))),
+    };
+    let mut regs = VcpuRegisters::new(vm, vcpu_id, paging);
+    let mut ram = GuestRam::new(vm);
+    return string_io(&io, exit.rip, exit.inst_length, &mut regs, &mut ram, ports);
+}
+
+/// Emulates the string port I/O instruction 'io' at 'rip', which is
+/// 'inst_length' bytes long, with the guest memory accessed through 'mem'.
+/// On success, RIP is advanced past the instruction, unless a REP prefixed
+/// instruction has iterations left. If a segment check or a translation
+/// faults, the iterations before it are kept, and the instruction is
+/// restarted. Segment faults are injected into the VCPU.
+pub fn string_io(io: &StringIo, rip: u64, inst_length: u64, regs: &mut dyn RegisterAccess,
+                 mem: &mut dyn MmioAccess, ports: &mut dyn PortHandler) -> Result<(), EmulateError> {
+    let index_reg = if io.is_in { vm_reg_name::VM_REG_GUEST_RDI } else { vm_reg_name::VM_REG_GUEST_RSI };
+    let bytes = io.bytes as u64;
+    let step = if (io.rflags & PSL_D) != 0 { bytes.wrapping_neg() } else { bytes };
+    let desc = SegmentDescriptor::from_desc(&io.seg_desc);
+    let kind = if io.is_in { FaultType::Write } else { FaultType::Read };
+
+    let mut index = io.index;
+    let mut done = 0;
+    let mut result = Ok(());
+    while done < io.count.min(STRING_IO_BATCH) {
+        result = match desc.linear_address(io.segment, io.cpu_mode, io.addrsize, index, io.bytes, kind) {
+            Ok(gla) => string_io_one(io, gla, regs, mem, ports),
+            Err(fault) => match regs.inject_exception(fault.vector(), 0) {
+                Ok(()) => Err(EmulateError::Fault),
+                Err(e) => Err(EmulateError::Sys(e)),
+            },
+        };
+        if result.is_err() {
+            break;
+        }
+        index = index.wrapping_add(step) & size_mask(io.addrsize);
+        done += 1;
+    }
+
+    add_register(regs, index_reg, step.wrapping_mul(done), io.addrsize)?;
+    let mut next_rip = rip + inst_length;
+    if io.repeat {
+        add_register(regs, vm_reg_name::VM_REG_GUEST_RCX, done.wrapping_neg(), io.addrsize)?;
+        if done < io.count {
+            next_rip = rip;
+        }
+    } else if result.is_err() {
+        next_rip = rip;
+    }
+    regs.set_register(vm_reg_name::VM_REG_GUEST_RIP, next_rip)?;
+    return result;
+}
+
+// Emulates one iteration of a string I/O instruction, with the buffer at the
+// linear address 'gla'.
+fn string_io_one(io: &StringIo, gla: u64, regs: &mut dyn RegisterAccess, mem: &mut dyn MmioAccess,
+                 ports: &mut dyn PortHandler) -> Result<(), EmulateError> {
+    // An access that crosses a page boundary is split into bytes, which
+    // are translated separately.
+    let crosses = (gla & 0xfff) + io.bytes as u64 > 0x1000;
+    if io.is_in {
+        // The port is only read once the whole destination is translated,
+        // since the value would be lost if a translation faulted.
+        if !crosses {
+            let gpa = translate(regs, gla, true)?;
+            let value = ports.port_in(io.port, io.bytes)? as u64;
+            mem.write(gpa, io.bytes, value)?;
+        } else {
+            let mut gpas = [0u64; 4];
+            let gpas = &mut gpas[..io.bytes as usize];
+            for (i, gpa) in gpas.iter_mut().enumerate() {
+                *gpa = translate(regs, gla.wrapping_add(i as u64), true)?;
+            }
+            let value = ports.port_in(io.port, io.bytes)? as u64;
+            for (i, gpa) in gpas.iter().enumerate() {
+                mem.write(*gpa, 1, value >> (i * 8))?;
+            }
+        }
+    } else {
+        let mut value = 0;
+        if !crosses {
+            let gpa = translate(regs, gla, false)?;
+            value = mem.read(gpa, io.bytes)?;
+        } else {
+            for i in 0..io.bytes as u64 {
+                let gpa = translate(regs, gla.wrapping_add(i), false)?;
+                value |= (mem.read(gpa, 1)? & 0xff) << (i * 8);
+            }
+        }
+        ports.port_out(io.port, io.bytes, value as u32)?;
+    }
+    return Ok(());
+}
+
+fn translate(regs: &mut dyn RegisterAccess, gla: u64, write: bool) -> Result<u64, EmulateError> {
+    match regs.translate(gla, write)? {
+        Translation::Gpa(gpa) => return Ok(gpa),
+        Translation::Fault => return Err(EmulateError::Fault),
+    }
+}
+
+// Reads an operand of 'size' bytes. A memory operand is read at 'gpa'.
+fn read_operand(operand: &Operand, size: u8, gpa: u64, regs: &mut dyn RegisterAccess,
+                mmio: &mut dyn MmioAccess) -> Result<u64, Error> {
+    let value = match *operand {
+        Operand::Register(reg) => regs.get_register(reg)?,
+        Operand::HighByte(reg) => regs.get_register(reg)? >> 8,
+        Operand::Memory(_) => mmio.read(gpa, size)?,
+        Operand::Immediate(imm) => imm as u64,
+    };
+    return Ok(value & size_mask(size));
+}
+
+// Writes an operand of 'size' bytes. A memory operand is written at 'gpa'.
+// Like the CPU, a 4-byte register write clears the upper half of the
+// register, and 1 and 2-byte writes leave the rest of the register alone.
+fn write_operand(operand: &Operand, size: u8, gpa: u64, value: u64, regs: &mut dyn RegisterAccess,
+                 mmio: &mut dyn MmioAccess) -> Result<(), Error> {
+    match *operand {
+        Operand::Register(reg) => {
+            let value = match size {
+                1 | 2 => {
+                    let mask = size_mask(size);
+                    (regs.get_register(reg)? & !mask) | (value & mask)
+                }
+                4 => value & 0xffffffff,
+                _ => value,
+            };
+            regs.set_register(reg, value)
+        }
+        Operand::HighByte(reg) => {
+            let value = (regs.get_register(reg)? & !0xff00) | ((value & 0xff) << 8);
+            regs.set_register(reg, value)
+        }
+        Operand::Memory(_) => mmio.write(gpa, size, value),
+        Operand::Immediate(_) => Err(Error::new(EINVAL)),
+    }
+}
+
+// Returns the guest physical address of an implicit memory operand of 'size'
+// bytes, which isn't necessarily the one that caused the exit. A segment
+// check that fails is injected into the VCPU.
+fn memory_gpa(operand: &Operand, size: u8, addrsize: u8, write: bool, cpu_mode: vm_cpu_mode,
+              next_rip: u64, regs: &mut dyn RegisterAccess) -> Result<u64, EmulateError> {
+    let mem: &MemoryOperand = match operand {
+        Operand::Memory(mem) => mem,
+        _ => return Err(EmulateError::NoMemoryOperand),
+    };
+
+    let mut result = Ok(0);
+    let ea = mem.effective_address(addrsize, next_rip, |reg| {
+        match regs.get_register(reg) {
+            Ok(value) => value,
+            Err(e) => {
+                result = Err(e);
+                0
+            }
+        }
+    });
+    result?;
+
+    let (base, limit, access) = regs.get_desc(mem.segment)?;
+    let desc = SegmentDescriptor::from_access(base, limit, access);
+    let kind = if write { FaultType::Write } else { FaultType::Read };
+    match desc.linear_address(mem.segment, cpu_mode, addrsize, ea, size, kind) {
+        Ok(gla) => return translate(regs, gla, write),
+        Err(fault) => {
+            regs.inject_exception(fault.vector(), 0)?;
+            return Err(EmulateError::Fault);
+        }
+    }
+}
+
+// Adds 'delta' to the low 'size' bytes of a register, leaving the rest of
+// the register alone, and returns the new value of the low bytes.
+fn add_register(regs: &mut dyn RegisterAccess, reg: vm_reg_name, delta: u64, size: u8) -> Result<u64, Error> {
+    let mask = size_mask(size);
+    let value = regs.get_register(reg)?;
+    let low = value.wrapping_add(delta) & mask;
+    regs.set_register(reg, (value & !mask) | low)?;
+    return Ok(low);
+}
+
+// The stack address size is 8 bytes in 64-bit mode, and otherwise set by
+// the B flag of the SS descriptor.
+fn stack_addrsize(cpu_mode: vm_cpu_mode, regs: &mut dyn RegisterAccess) -> Result<u8, Error> {
+    if cpu_mode == vm_cpu_mode::CPU_MODE_64BIT {
+        return Ok(8);
+    }
+    let (base, limit, access) = regs.get_desc(vm_reg_name::VM_REG_GUEST_SS)?;
+    if SegmentDescriptor::from_access(base, limit, access).default_big {
+        return Ok(4);
+    } else {
+        return Ok(2);
+    }
+}
+
+fn sign_extend(value: u64, size: u8) -> u64 {
+    let shift = 64 - (size as u32 * 8);
+    (((value << shift) as i64) >> shift) as u64
+}
+
+// Computes the result of an arithmetic or logic operation on operands of
+// 'size' bytes, and the status flags of RFLAGS that it sets.
+fn alu(op: Operation, dst: u64, src: u64, carry: bool, size: u8) -> (u64, u64) {
+    let mask = size_mask(size);
+    let sign = 1 << (size as u64 * 8 - 1);
+    let (dst, src) = (dst & mask, src & mask);
+    let carry_in = if carry { 1 } else { 0 };
+    let mut flags = 0;
+
+    let result = match op {
+        Operation::Add | Operation::Adc => {
+            let c = if op == Operation::Adc { carry_in } else { 0 };
+            let wide = dst as u128 + src as u128 + c as u128;
+            let result = (wide as u64) & mask;
+            if (wide >> (size as u32 * 8)) != 0 {
+                flags |= PSL_C;
+            }
+            if ((dst ^ result) & (src ^ result) & sign) != 0 {
+                flags |= PSL_V;
+            }
+            if ((dst ^ src ^ result) & 0x10) != 0 {
+                flags |= PSL_AF;
+            }
+            result
+        }
+        Operation::Sub | Operation::Sbb | Operation::Cmp => {
+            let c = if op == Operation::Sbb { carry_in } else { 0 };
+            let result = dst.wrapping_sub(src).wrapping_sub(c) & mask;
+            if (dst as u128) < (src as u128 + c as u128) {
+                flags |= PSL_C;
+            }
+            if ((dst ^ src) & (dst ^ result) & sign) != 0 {
+                flags |= PSL_V;
+            }
+            if ((dst ^ src ^ result) & 0x10) != 0 {
+                flags |= PSL_AF;
+            }
+            result
+        }
+        Operation::And | Operation::Test => dst & src,
+        Operation::Or => dst | src,
+        Operation::Xor => dst ^ src,
+        _ => dst,
+    };
+
+    if result == 0 {
+        flags |= PSL_Z;
+    }
+    if (result & sign) != 0 {
+        flags |= PSL_N;
+    }
+    if (result as u8).count_ones().is_multiple_of(2) {
+        flags |= PSL_PF;
+    }
+    return (result, flags);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::decode::decode;
+    use crate::emulate::*;
+    use crate::include::segments::{IDT_GP, IDT_SS};
+    use crate::vm::vm_cpu_mode::*;
+    use crate::vm::vm_reg_name::*;
+
+    // An in-memory CPU, with paging disabled and flat segments unless others
+    // are set. Records the exceptions injected into it. Translations of
+    // linear addresses in 'fault_page' fault.
+    #[derive(Default)]
+    struct TestCpu {
+        regs: HashMap<u32, u64>,
+        descs: HashMap<u32, SegmentDescriptor>,
+        exceptions: Vec<i32>,
+        fault_page: Option<u64>,
+    }
+
+    impl TestCpu {
+        fn get(&self, reg: vm_reg_name) -> u64 {
+            *self.regs.get(&(reg as u32)).unwrap_or(&0)
+        }
+
+        fn set(&mut self, reg: vm_reg_name, value: u64) {
+            self.regs.insert(reg as u32, value);
+        }
+    }
+
+    impl RegisterAccess for TestCpu {
+        fn get_register(&mut self, reg: vm_reg_name) -> Result<u64, Error> {
+            Ok(self.get(reg))
+        }
+
+        fn set_register(&mut self, reg: vm_reg_name, value: u64) -> Result<(), Error> {
+            self.set(reg, value);
+            Ok(())
+        }
+
+        fn get_desc(&mut self, reg: vm_reg_name) -> Result<(u64, u32, u32), Error> {
+            match self.descs.get(&(reg as u32)) {
+                Some(desc) => Ok((desc.base, desc.limit, desc.access())),
+                None => Ok((0, 0xffffffff, 0xc093)),
+            }
+        }
+
+        fn inject_exception(&mut self, vector: i32, _errcode: u32) -> Result<(), Error> {
+            self.exceptions.push(vector);
+            Ok(())
+        }
+
+        fn translate(&mut self, gla: u64, _write: bool) -> Result<Translation, Error> {
+            if self.fault_page == Some(gla & !0xfff) {
+                return Ok(Translation::Fault);
+            }
+            Ok(Translation::Gpa(gla))
+        }
+    }
+
+    // Byte-addressed guest memory, which records the accesses.
+    #[derive(Default)]
+    struct TestMemory {
+        bytes: HashMap<u64, u8>,
+        accesses: Vec<(u64, u8, bool)>,
+    }
+
+    impl MmioAccess for TestMemory {
+        fn read(&mut self, gpa: u64, size: u8) -> Result<u64, Error> {
+            self.accesses.push((gpa, size, false));
+            let mut value = 0;
+            for i in 0..size as u64 {
+                value |= (*self.bytes.get(&(gpa + i)).unwrap_or(&0) as u64) << (i * 8);
+            }
+            Ok(value)
+        }
+
+        fn write(&mut self, gpa: u64, size: u8, value: u64) -> Result<(), Error> {
+            self.accesses.push((gpa, size, true));
+            for i in 0..size as u64 {
+                self.bytes.insert(gpa + i, (value >> (i * 8)) as u8);
+            }
+            Ok(())
+        }
+    }
+
+    const RIP: u64 = 0x1000;
+    const GPA: u64 = 0xfee0_0000;
+
+    fn run(bytes: &[u8], cpu: &mut TestCpu, mem: &mut TestMemory) {
+        let inst = decode(bytes, CPU_MODE_64BIT, false).unwrap();
+        emulate(&inst, GPA, RIP, CPU_MODE_64BIT, cpu, mem).unwrap();
+    }
+
+    #[test]
+    fn test_mov() {
+        let mut cpu = TestCpu::default();
+        let mut mem = TestMemory::default();
+
+        // mov %eax, (%rbx)
+        cpu.set(VM_REG_GUEST_RAX, 0x1122334455667788);
+        run(&[0x89, 0x03], &mut cpu, &mut mem);
+        assert_eq!(mem.accesses, vec![(GPA, 4, true)]);
+        assert_eq!(mem.read(GPA, 8).unwrap(), 0x55667788);
+        assert_eq!(cpu.get(VM_REG_GUEST_RIP), RIP + 2);
+
+        // mov (%rbx), %ecx clears the upper half of RCX
+        cpu.set(VM_REG_GUEST_RCX, !0);
+        run(&[0x8b, 0x0b], &mut cpu, &mut mem);
+        assert_eq!(cpu.get(VM_REG_GUEST_RCX), 0x55667788);
+
+        // mov (%rbx), %dh only changes bits 15:8 of RDX
+        cpu.set(VM_REG_GUEST_RDX, 0xaaaa);
+        run(&[0x8a, 0x33], &mut cpu, &mut mem);
+        assert_eq!(cpu.get(VM_REG_GUEST_RDX), 0x88aa);
+
+        // movw $0x1234, (%rbx)
+        run(&[0x66, 0xc7, 0x03, 0x34, 0x12], &mut cpu, &mut mem);
+        assert_eq!(mem.read(GPA, 4).unwrap(), 0x55661234);
+        assert_eq!(cpu.get(VM_REG_GUEST_RIP), RIP + 5);
+    }
+
+    #[test]
+    fn test_movzx_movsx() {
+        let mut cpu = TestCpu::default();
+        let mut mem = TestMemory::default();
+        mem.write(GPA, 2, 0x80f0).unwrap();
+
+        // movzbl (%rax), %ecx
+        run(&[0x0f, 0xb6, 0x08], &mut cpu, &mut mem);
+        assert_eq!(cpu.get(VM_REG_GUEST_RCX), 0xf0);
+
+        // movswq (%rax), %rcx
+        run(&[0x48, 0x0f, 0xbf, 0x08], &mut cpu, &mut mem);
+        assert_eq!(cpu.get(VM_REG_GUEST_RCX), 0xffffffffffff80f0);
+    }
+
+    #[test]
+    fn test_flags() {
+        let mut cpu = TestCpu::default();
+        let mut mem = TestMemory::default();
+        let rflags = |cpu: &TestCpu| cpu.get(VM_REG_GUEST_RFLAGS) & PSL_STATUS;
+
+        // cmpl $0x1, (%rax) with 0 in memory borrows
+        run(&[0x83, 0x38, 0x01], &mut cpu, &mut mem);
+        assert_eq!(rflags(&cpu), PSL_C | PSL_N | PSL_AF | PSL_PF);
+        assert!(mem.accesses.iter().all(|&(_, _, write)| !write));
+
+        // addb $0x7f, (%rax) with 0x01 in memory overflows
+        mem.write(GPA, 1, 0x01).unwrap();
+        run(&[0x80, 0x00, 0x7f], &mut cpu, &mut mem);
+        assert_eq!(mem.read(GPA, 1).unwrap(), 0x80);
+        assert_eq!(rflags(&cpu), PSL_V | PSL_N | PSL_AF);
+
+        // addl $0x1, (%rax) with 0xffffffff in memory carries
+        mem.write(GPA, 4, 0xffffffff).unwrap();
+        run(&[0x83, 0x00, 0x01], &mut cpu, &mut mem);
+        assert_eq!(mem.read(GPA, 4).unwrap(), 0);
+        assert_eq!(rflags(&cpu), PSL_C | PSL_Z | PSL_AF | PSL_PF);
+
+        // adcl $0x0, (%rax) adds the carry
+        run(&[0x83, 0x10, 0x00], &mut cpu, &mut mem);
+        assert_eq!(mem.read(GPA, 4).unwrap(), 1);
+        assert_eq!(rflags(&cpu), 0);
+
+        // andl $0xfffffffe, (%rax) clears carry and overflow
+        cpu.set(VM_REG_GUEST_RFLAGS, PSL_C | PSL_V | 0x2);
+        run(&[0x83, 0x20, 0xfe], &mut cpu, &mut mem);
+        assert_eq!(mem.read(GPA, 4).unwrap(), 0);
+        assert_eq!(cpu.get(VM_REG_GUEST_RFLAGS), PSL_Z | PSL_PF | 0x2);
+
+        // orl %ecx, (%rax) and testl %ecx, (%rax)
+        cpu.set(VM_REG_GUEST_RCX, 0x80000001);
+        run(&[0x09, 0x08], &mut cpu, &mut mem);
+        assert_eq!(mem.read(GPA, 4).unwrap(), 0x80000001);
+        run(&[0x85, 0x08], &mut cpu, &mut mem);
+        assert_eq!(rflags(&cpu), PSL_N);
+
+        // subl (%rax), %ecx writes the register
+        run(&[0x2b, 0x08], &mut cpu, &mut mem);
+        assert_eq!(cpu.get(VM_REG_GUEST_RCX), 0);
+        assert_eq!(rflags(&cpu), PSL_Z | PSL_PF);
+
+        // btl $0x1f, (%rax)
+        run(&[0x0f, 0xba, 0x20, 0x1f], &mut cpu, &mut mem);
+        assert_eq!(rflags(&cpu) & PSL_C, PSL_C);
+    }
+
+    #[test]
+    fn test_stos() {
+        let mut cpu = TestCpu::default();
+        let mut mem = TestMemory::default();
+
+        // rep stosl, with the direction flag set
+        cpu.set(VM_REG_GUEST_RAX, 0xdeadbeef);
+        cpu.set(VM_REG_GUEST_RDI, GPA + 8);
+        cpu.set(VM_REG_GUEST_RCX, 2);
+        cpu.set(VM_REG_GUEST_RFLAGS, PSL_D);
+        run(&[0xf3, 0xab], &mut cpu, &mut mem);
+        assert_eq!(mem.read(GPA + 8, 4).unwrap(), 0xdeadbeef);
+        assert_eq!(cpu.get(VM_REG_GUEST_RDI), GPA + 4);
+        assert_eq!(cpu.get(VM_REG_GUEST_RCX), 1);
+        assert_eq!(cpu.get(VM_REG_GUEST_RIP), RIP);
+
+        run(&[0xf3, 0xab], &mut cpu, &mut mem);
+        assert_eq!(mem.read(GPA + 4, 4).unwrap(), 0xdeadbeef);
+        assert_eq!(cpu.get(VM_REG_GUEST_RCX), 0);
+        assert_eq!(cpu.get(VM_REG_GUEST_RIP), RIP + 2);
+
+        // rep stosl with a count of 0 does nothing
+        mem.accesses.clear();
+        run(&[0xf3, 0xab], &mut cpu, &mut mem);
+        assert!(mem.accesses.is_empty());
+        assert_eq!(cpu.get(VM_REG_GUEST_RIP), RIP + 2);
+    }
+
+    #[test]
+    fn test_movs_push_pop() {
+        let mut cpu = TestCpu::default();
+        let mut mem = TestMemory::default();
+
+        // movsw
+        mem.write(0x2000, 2, 0xabcd).unwrap();
+        cpu.set(VM_REG_GUEST_RSI, 0x2000);
+        cpu.set(VM_REG_GUEST_RDI, GPA);
+        run(&[0x66, 0xa5], &mut cpu, &mut mem);
+        assert_eq!(mem.read(GPA, 2).unwrap(), 0xabcd);
+        assert_eq!(cpu.get(VM_REG_GUEST_RSI), 0x2002);
+        assert_eq!(cpu.get(VM_REG_GUEST_RDI), GPA + 2);
+
+        // pushq (%rax)
+        cpu.set(VM_REG_GUEST_RSP, 0x8000);
+        run(&[0xff, 0x30], &mut cpu, &mut mem);
+        assert_eq!(cpu.get(VM_REG_GUEST_RSP), 0x7ff8);
+        assert_eq!(mem.read(0x7ff8, 8).unwrap(), 0xabcd);
+
+        // popq (%rax)
+        mem.write(0x7ff8, 8, 0x1234).unwrap();
+        run(&[0x8f, 0x00], &mut cpu, &mut mem);
+        assert_eq!(cpu.get(VM_REG_GUEST_RSP), 0x8000);
+        assert_eq!(mem.read(GPA, 8).unwrap(), 0x1234);
+    }
+
+    #[test]
+    fn test_segments() {
+        let mut cpu = TestCpu::default();
+        let mut mem = TestMemory::default();
+        let run32 = |bytes: &[u8], cpu: &mut TestCpu, mem: &mut TestMemory| {
+            let inst = decode(bytes, CPU_MODE_PROTECTED, true).unwrap();
+            emulate(&inst, GPA, RIP, CPU_MODE_PROTECTED, cpu, mem)
+        };
+
+        // movsw reads through the DS base
+        let ds = SegmentDescriptor { limit: 0x1fff, ..SegmentDescriptor::real_mode(0x10000) };
+        cpu.descs.insert(VM_REG_GUEST_DS as u32, ds);
+        mem.write(0x10100, 2, 0xabcd).unwrap();
+        cpu.set(VM_REG_GUEST_RSI, 0x100);
+        cpu.set(VM_REG_GUEST_RDI, GPA);
+        run32(&[0x66, 0xa5], &mut cpu, &mut mem).unwrap();
+        assert_eq!(mem.read(GPA, 2).unwrap(), 0xabcd);
+
+        // A source that extends past the DS limit injects #GP and leaves RIP
+        mem.accesses.clear();
+        cpu.set(VM_REG_GUEST_RIP, RIP);
+        cpu.set(VM_REG_GUEST_RSI, 0x1fff);
+        match run32(&[0x66, 0xa5], &mut cpu, &mut mem) {
+            Err(EmulateError::Fault) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(cpu.exceptions, vec![IDT_GP]);
+        assert!(mem.accesses.is_empty());
+        assert_eq!(cpu.get(VM_REG_GUEST_RIP), RIP);
+
+        // pushl (%eax) on a 16-bit stack below the SS limit injects #SS
+        let ss = SegmentDescriptor { limit: 0xfff, ..SegmentDescriptor::from_access(0x20000, 0, 0x0097) };
+        cpu.descs.insert(VM_REG_GUEST_SS as u32, ss);
+        cpu.set(VM_REG_GUEST_RSP, 0x1002);
+        assert!(run32(&[0xff, 0x30], &mut cpu, &mut mem).is_err());
+        assert_eq!(cpu.exceptions, vec![IDT_GP, IDT_SS]);
+        assert_eq!(cpu.get(VM_REG_GUEST_RSP), 0x1002);
+
+        // The DS base is ignored in 64-bit mode
+        cpu.set(VM_REG_GUEST_RSI, 0x100);
+        cpu.set(VM_REG_GUEST_RDI, GPA);
+        mem.write(0x100, 2, 0x5678).unwrap();
+        run(&[0x66, 0xa5], &mut cpu, &mut mem);
+        assert_eq!(mem.read(GPA, 2).unwrap(), 0x5678);
+    }
+
+    // A port that returns increasing values, and records the values written.
+    #[derive(Default)]
+    struct TestPorts {
+        next_in: u32,
+        out: Vec<u32>,
+    }
+
+    impl PortHandler for TestPorts {
+        fn port_in(&mut self, port: u16, bytes: u8) -> Result<u32, Error> {
+            assert_eq!((port, bytes), (0x1f0, 2));
+            self.next_in += 1;
+            Ok(self.next_in)
+        }
+
+        fn port_out(&mut self, port: u16, bytes: u8, value: u32) -> Result<(), Error> {
+            assert_eq!((port, bytes), (0x1f0, 2));
+            self.out.push(value);
+            Ok(())
+        }
+    }
+
+    fn string_io_exit(is_in: bool, repeat: bool, index: u64, count: u64) -> StringIo {
+        StringIo {
+            port: 0x1f0,
+            bytes: 2,
+            is_in: is_in,
+            repeat: repeat,
+            addrsize: 8,
+            index: index,
+            count: count,
+            rflags: 0x2,
+            segment: VM_REG_GUEST_ES,
+            seg_desc: seg_desc { base: 0, limit: 0xffffffff, access: 0xc093 },
+            cpu_mode: CPU_MODE_64BIT,
+        }
+    }
+
+    #[test]
+    fn test_string_io() {
+        let mut cpu = TestCpu::default();
+        let mut mem = TestMemory::default();
+        let mut ports = TestPorts::default();
+
+        // rep insw with 20 iterations takes two exits
+        cpu.set(VM_REG_GUEST_RDI, 0x1000);
+        cpu.set(VM_REG_GUEST_RCX, 20);
+        let io = string_io_exit(true, true, 0x1000, 20);
+        string_io(&io, RIP, 2, &mut cpu, &mut mem, &mut ports).unwrap();
+        assert_eq!(cpu.get(VM_REG_GUEST_RDI), 0x1020);
+        assert_eq!(cpu.get(VM_REG_GUEST_RCX), 4);
+        assert_eq!(cpu.get(VM_REG_GUEST_RIP), RIP);
+
+        let io = string_io_exit(true, true, 0x1020, 4);
+        string_io(&io, RIP, 2, &mut cpu, &mut mem, &mut ports).unwrap();
+        assert_eq!(cpu.get(VM_REG_GUEST_RDI), 0x1028);
+        assert_eq!(cpu.get(VM_REG_GUEST_RCX), 0);
+        assert_eq!(cpu.get(VM_REG_GUEST_RIP), RIP + 2);
+        for i in 0..20 {
+            assert_eq!(mem.read(0x1000 + i * 2, 2).unwrap(), i + 1);
+        }
+
+        // outsw with the direction flag set, across a page boundary
+        mem.write(0x1fff, 2, 0xbeef).unwrap();
+        cpu.set(VM_REG_GUEST_RSI, 0x1fff);
+        let mut io = string_io_exit(false, false, 0x1fff, 1);
+        io.rflags |= PSL_D;
+        string_io(&io, RIP, 2, &mut cpu, &mut mem, &mut ports).unwrap();
+        assert_eq!(ports.out, vec![0xbeef]);
+        assert_eq!(cpu.get(VM_REG_GUEST_RSI), 0x1ffd);
+        assert_eq!(cpu.get(VM_REG_GUEST_RCX), 0);
+        assert_eq!(cpu.get(VM_REG_GUEST_RIP), RIP + 2);
+
+        // The index wraps at the address size
+        cpu.set(VM_REG_GUEST_RDI, 0x1_0000_fffe);
+        let mut io = string_io_exit(true, false, 0xfffe, 1);
+        io.addrsize = 2;
+        string_io(&io, RIP, 3, &mut cpu, &mut mem, &mut ports).unwrap();
+        assert_eq!(cpu.get(VM_REG_GUEST_RDI), 0x1_0000_0000);
+        assert_eq!(cpu.get(VM_REG_GUEST_RIP), RIP + 3);
+    }
+
+    #[test]
+    fn test_string_io_fault() {
+        let mut cpu = TestCpu::default();
+        let mut mem = TestMemory::default();
+        let mut ports = TestPorts::default();
+
+        // insw across a page boundary, where the second page faults, doesn't
+        // read the port
+        cpu.fault_page = Some(0x2000);
+        cpu.set(VM_REG_GUEST_RDI, 0x1fff);
+        let io = string_io_exit(true, false, 0x1fff, 1);
+        match string_io(&io, RIP, 2, &mut cpu, &mut mem, &mut ports) {
+            Err(EmulateError::Fault) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(ports.next_in, 0);
+        assert!(mem.accesses.is_empty());
+        assert_eq!(cpu.get(VM_REG_GUEST_RDI), 0x1fff);
+        assert_eq!(cpu.get(VM_REG_GUEST_RIP), RIP);
+
+        // rep insw keeps the iterations before the fault
+        cpu.set(VM_REG_GUEST_RDI, 0x1ffa);
+        cpu.set(VM_REG_GUEST_RCX, 4);
+        let io = string_io_exit(true, true, 0x1ffa, 4);
+        assert!(string_io(&io, RIP, 2, &mut cpu, &mut mem, &mut ports).is_err());
+        assert_eq!(ports.next_in, 3);
+        assert_eq!(cpu.get(VM_REG_GUEST_RDI), 0x2000);
+        assert_eq!(cpu.get(VM_REG_GUEST_RCX), 1);
+        assert_eq!(cpu.get(VM_REG_GUEST_RIP), RIP);
+    }
+
+    #[test]
+    fn test_string_io_segments() {
+        let mut cpu = TestCpu::default();
+        let mut mem = TestMemory::default();
+        let mut ports = TestPorts::default();
+
+        // In 64-bit mode, the ES base is ignored
+        cpu.set(VM_REG_GUEST_RDI, 0x1000);
+        let mut io = string_io_exit(true, false, 0x1000, 1);
+        io.seg_desc.base = 0x10_0000;
+        string_io(&io, RIP, 2, &mut cpu, &mut mem, &mut ports).unwrap();
+        assert_eq!(mem.accesses, vec![(0x1000, 2, true)]);
+
+        // In protected mode, the ES base applies
+        cpu.set(VM_REG_GUEST_RDI, 0x1000);
+        io.cpu_mode = CPU_MODE_PROTECTED;
+        io.addrsize = 4;
+        string_io(&io, RIP, 2, &mut cpu, &mut mem, &mut ports).unwrap();
+        assert_eq!(mem.accesses[1], (0x10_1000, 2, true));
+
+        // An insw past the ES limit injects #GP rather than writing memory
+        mem.accesses.clear();
+        cpu.set(VM_REG_GUEST_RCX, 2);
+        let mut io = string_io_exit(true, true, 0xfffd, 2);
+        io.cpu_mode = CPU_MODE_PROTECTED;
+        io.addrsize = 4;
+        io.seg_desc.limit = 0xffff;
+        io.seg_desc.access = 0x4093;
+        cpu.set(VM_REG_GUEST_RDI, 0xfffd);
+        match string_io(&io, RIP, 2, &mut cpu, &mut mem, &mut ports) {
+            Err(EmulateError::Fault) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(cpu.exceptions, vec![IDT_GP]);
+        assert_eq!(mem.accesses, vec![(0xfffd, 2, true)]);
+        assert_eq!(cpu.get(VM_REG_GUEST_RDI), 0xffff);
+        assert_eq!(cpu.get(VM_REG_GUEST_RCX), 1);
+        assert_eq!(cpu.get(VM_REG_GUEST_RIP), RIP);
+
+        // So does an insw to a read-only ES
+        cpu.exceptions.clear();
+        let mut io = string_io_exit(true, false, 0x1000, 1);
+        io.cpu_mode = CPU_MODE_PROTECTED;
+        io.addrsize = 4;
+        io.seg_desc.access = 0xc091;
+        assert!(string_io(&io, RIP, 2, &mut cpu, &mut mem, &mut ports).is_err());
+        assert_eq!(cpu.exceptions, vec![IDT_GP]);
+    }
+
+    #[test]
+    fn test_no_memory_operand() {
+        let mut cpu = TestCpu::default();
+        let mut mem = TestMemory::default();
+
+        // add %ecx, %eax
+        let inst = decode(&[0x01, 0xc8], CPU_MODE_64BIT, false).unwrap();
+        match emulate(&inst, GPA, RIP, CPU_MODE_64BIT, &mut cpu, &mut mem) {
+            Err(EmulateError::NoMemoryOperand) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(cpu.get(VM_REG_GUEST_RIP), 0);
+    }
+}
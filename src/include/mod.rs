@@ -1,3 +1,6 @@
 pub mod vmm;
 pub mod vmm_dev;
 pub mod specialreg;
+pub mod segments;
+pub mod psl;
+pub mod pte;
@@ -0,0 +1,13 @@
+//! Constants for interfacing with the Bhyve ioctl interface.
+//!
+//! These are defined in Rust, but mimic the C constants defined
+//! in `machine/psl.h`.
+
+pub const PSL_C: u64 = 0x00000001;     // carry bit
+pub const PSL_PF: u64 = 0x00000004;    // parity bit
+pub const PSL_AF: u64 = 0x00000010;    // bcd carry bit
+pub const PSL_Z: u64 = 0x00000040;     // zero bit
+pub const PSL_N: u64 = 0x00000080;     // negative bit
+pub const PSL_I: u64 = 0x00000200;     // interrupt enable bit
+pub const PSL_D: u64 = 0x00000400;     // string instruction direction bit
+pub const PSL_V: u64 = 0x00000800;     // overflow bit
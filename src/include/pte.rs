@@ -0,0 +1,24 @@
+//! Constants for interfacing with the Bhyve ioctl interface.
+//!
+//! These are defined in Rust, but mimic the C constants defined
+//! in `machine/pmap.h`.
+
+// Page table entry bits
+pub const PG_V: u64 = 0x001;            // P       Valid
+pub const PG_RW: u64 = 0x002;           // R/W     Read/Write
+pub const PG_U: u64 = 0x004;            // U/S     User/Supervisor
+pub const PG_A: u64 = 0x020;            // A       Accessed
+pub const PG_M: u64 = 0x040;            // D       Dirty
+pub const PG_PS: u64 = 0x080;           // PS      Page size (0=4k,1=2M)
+pub const PG_NX: u64 = 1 << 63;         // No-execute
+pub const PG_FRAME: u64 = 0x000f_ffff_ffff_f000;
+
+// PG_FRAME of 32-bit paging, from the i386 `machine/pmap.h`
+pub const PG_FRAME32: u64 = 0xffff_f000;
+
+// Page fault error codes
+pub const PGEX_P: u32 = 0x01;           // Protection violation vs. not present
+pub const PGEX_W: u32 = 0x02;           // during a Write cycle
+pub const PGEX_U: u32 = 0x04;           // access from User mode (UPL)
+pub const PGEX_RSV: u32 = 0x08;         // reserved PTE field is non-zero
+pub const PGEX_I: u32 = 0x10;           // during an instruction fetch
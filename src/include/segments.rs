@@ -0,0 +1,7 @@
+//! Constants for interfacing with the Bhyve ioctl interface.
+//!
+//! These are defined in Rust, but mimic the C constants defined
+//! in `machine/segments.h`.
+
+pub const IDT_SS: i32 = 12;     // #SS: Stack Fault
+pub const IDT_GP: i32 = 13;     // #GP: General Protection Fault
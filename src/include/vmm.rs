@@ -23,7 +23,7 @@ pub enum vm_suspend_how {
 // Identifiers for architecturally defined registers.
 #[repr(C)]
 #[allow(non_camel_case_types, unused)]
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum vm_reg_name {
         VM_REG_GUEST_RAX,
         VM_REG_GUEST_RBX,
@@ -72,6 +72,34 @@ pub enum vm_reg_name {
         VM_REG_LAST
 }
 
+impl vm_reg_name {
+    /// Returns the general purpose register with the index 'index' in the x86
+    /// instruction encoding (RAX, RCX, RDX, RBX, RSP, RBP, RSI, RDI, R8-R15),
+    /// as used in ModRM fields and VM exit qualifications.
+    pub fn from_gpr_index(index: u8) -> Option<vm_reg_name> {
+        let reg = match index {
+            0 => vm_reg_name::VM_REG_GUEST_RAX,
+            1 => vm_reg_name::VM_REG_GUEST_RCX,
+            2 => vm_reg_name::VM_REG_GUEST_RDX,
+            3 => vm_reg_name::VM_REG_GUEST_RBX,
+            4 => vm_reg_name::VM_REG_GUEST_RSP,
+            5 => vm_reg_name::VM_REG_GUEST_RBP,
+            6 => vm_reg_name::VM_REG_GUEST_RSI,
+            7 => vm_reg_name::VM_REG_GUEST_RDI,
+            8 => vm_reg_name::VM_REG_GUEST_R8,
+            9 => vm_reg_name::VM_REG_GUEST_R9,
+            10 => vm_reg_name::VM_REG_GUEST_R10,
+            11 => vm_reg_name::VM_REG_GUEST_R11,
+            12 => vm_reg_name::VM_REG_GUEST_R12,
+            13 => vm_reg_name::VM_REG_GUEST_R13,
+            14 => vm_reg_name::VM_REG_GUEST_R14,
+            15 => vm_reg_name::VM_REG_GUEST_R15,
+            _ => return None,
+        };
+        Some(reg)
+    }
+}
+
 #[repr(C)]
 #[allow(non_camel_case_types, unused)]
 #[derive(Copy, Clone)]
@@ -95,13 +123,21 @@ pub enum vm_cap_type {
 }
 
 
+#[repr(C)]
+#[allow(non_camel_case_types, unused)]
+#[derive(Copy, Clone)]
+pub enum vm_intr_trigger {
+        EDGE_TRIGGER,
+        LEVEL_TRIGGER
+}
+
 // The 'access' field has the format specified in Table 21-2 of the Intel
 // Architecture Manual vol 3b.
 //
 // XXX The contents of the 'access' field are architecturally defined except
 // bit 16 - Segment Unusable.
 #[repr(C)]
-#[derive(Copy, Clone, Default)]
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
 pub struct seg_desc {
     pub base: c_ulonglong,
     pub limit: c_uint,
@@ -110,7 +146,7 @@ pub struct seg_desc {
 
 #[repr(C)]
 #[allow(non_camel_case_types, unused)]
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum vm_cpu_mode {
         CPU_MODE_REAL,
         CPU_MODE_PROTECTED,
@@ -120,7 +156,7 @@ pub enum vm_cpu_mode {
 
 #[repr(C)]
 #[allow(non_camel_case_types, unused)]
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum vm_paging_mode {
         PAGING_MODE_FLAT,
         PAGING_MODE_32,
@@ -129,7 +165,7 @@ pub enum vm_paging_mode {
 }
 
 #[repr(C)]
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone)]
 pub struct vm_guest_paging {
     pub cr3: c_ulonglong,
     pub cpl: c_int,
@@ -141,9 +177,10 @@ pub struct vm_guest_paging {
 // consumers of instruction decoding. The only reason why their contents
 // need to be exposed is because they are part of the 'vm_exit' structure.
 //
-// These structs are not public, and should never be used. They are
-// defined solely to allow Rust to calculate adequate memory allocation
-// for the 'vm_exit' struct.
+// These structs are not public. They are defined to allow Rust to
+// calculate adequate memory allocation for the 'vm_exit' struct, and
+// only the fetched instruction bytes are exposed, through
+// 'vm_exit_inst_emul::inst_bytes'.
 
 #[repr(C)]
 #[derive(Copy, Clone)]
@@ -153,17 +190,13 @@ struct vie_op {
     op_flags: u16,
 }
 
-// For now, this is punting on C bitfields by allocating arrays of bytes.
-// If we end up using this code, we will want some more precise way to
-// mimic C bitfields, like David Tolnay's #[bitfield] attribute macro.
-
 #[repr(C)]
 #[derive(Copy, Clone)]
 struct vie {
     inst: [u8; 15],
     num_valid: u8,
     num_processed: u8,
-    bitfields: [u8; 5], // This is two bits too large
+    bitfields: [u8; 5],
     disp_bytes: u8,
     imm_bytes: u8,
     scale: u8,
@@ -176,6 +209,32 @@ struct vie {
     op: vie_op,
 }
 
+// The C compiler packs the 'uint8_t' bitfields of 'vie' into five bytes.
+// A bitfield that doesn't fit in the rest of its byte starts a new byte,
+// so 'index' skips the two bits left after 'ss'.
+bitfield! {
+    vie.bitfields {
+        addrsize, set_addrsize: u8 [0; 4];
+        opsize, set_opsize: u8 [4; 4];
+        rex_w, set_rex_w: bool [8; 1];
+        rex_r, set_rex_r: bool [9; 1];
+        rex_x, set_rex_x: bool [10; 1];
+        rex_b, set_rex_b: bool [11; 1];
+        rex_present, set_rex_present: bool [12; 1];
+        repz_present, set_repz_present: bool [13; 1];
+        repnz_present, set_repnz_present: bool [14; 1];
+        opsize_override, set_opsize_override: bool [15; 1];
+        addrsize_override, set_addrsize_override: bool [16; 1];
+        segment_override, set_segment_override: bool [17; 1];
+        modrm_mod, set_modrm_mod: u8 [18; 2];
+        modrm_reg, set_modrm_reg: u8 [20; 4];
+        modrm_rm, set_modrm_rm: u8 [24; 4];
+        sib_ss, set_sib_ss: u8 [28; 2];
+        sib_index, set_sib_index: u8 [32; 4];
+        sib_base, set_sib_base: u8 [36; 4];
+    }
+}
+
 #[repr(C)]
 #[allow(non_camel_case_types, unused)]
 #[derive(Copy, Clone)]
@@ -210,43 +269,17 @@ pub enum vm_exitcode {
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct vm_inout {
-    bitfields: u16,
+    bitfields: [u8; 2],
     pub port: u16,
     pub eax: u32,
 }
 
-impl vm_inout {
-    pub fn bytes(&self) -> u16 {
-        // We only care about the first three bits of the bitfield
-        let bytes = self.bitfields & 0b0111;
-        return bytes;
-    }
-    pub fn is_in(&self) -> bool {
-        // We only care about the fourth bit of the bitfield
-        let mask: u16 = 0b01000;
-        if (self.bitfields & mask) == mask {
-            return true;
-        } else {
-            return false;
-        }
-    }
-    pub fn is_string(&self) -> bool {
-        // We only care about the fifth bit of the bitfield
-        let mask: u16 = 0b010000;
-        if (self.bitfields & mask) == mask {
-            return true;
-        } else {
-            return false;
-        }
-    }
-    pub fn is_repeat(&self) -> bool {
-        // We only care about the sixth bit of the bitfield
-        let mask: u16 = 0b0100000;
-        if (self.bitfields & mask) == mask {
-            return true;
-        } else {
-            return false;
-        }
+bitfield! {
+    vm_inout.bitfields {
+        pub bytes, set_bytes: u16 [0; 3];
+        pub is_in, set_in: bool [3; 1];
+        pub is_string, set_string: bool [4; 1];
+        pub is_repeat, set_repeat: bool [5; 1];
     }
 }
 
@@ -277,12 +310,12 @@ pub enum task_switch_reason {
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct vm_task_switch {
-    tsssel: u16,                         // new TSS selector
-    ext: c_int,                          // task switch due to external event
-    errcode: c_uint,
-    errcode_valid: c_int,                // push 'errcode' on the new stack
-    reason: task_switch_reason,
-    paging: vm_guest_paging,
+    pub tsssel: u16,                     // new TSS selector
+    pub ext: c_int,                      // task switch due to external event
+    pub errcode: c_uint,
+    pub errcode_valid: c_int,            // push 'errcode' on the new stack
+    pub reason: task_switch_reason,
+    pub paging: vm_guest_paging,
 }
 
 #[repr(C)]
@@ -343,6 +376,15 @@ pub struct vm_exit_inst_emul {
     vie: vie,
 }
 
+impl vm_exit_inst_emul {
+    // Returns the instruction bytes fetched by the kernel. This is empty if
+    // the kernel didn't fetch the instruction.
+    pub fn inst_bytes(&self) -> &[u8] {
+        let len = std::cmp::min(self.vie.num_valid as usize, self.vie.inst.len());
+        &self.vie.inst[..len]
+    }
+}
+
 // VMX specific payload. Used when there is no "better"
 // exitcode to represent the VM-exit.
 #[repr(C)]
@@ -401,3 +443,80 @@ pub struct vm_exit_ioapic_eoi {
 pub struct vm_exit_suspended {
     pub how: vm_suspend_how,
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::include::vmm::*;
+    use std::mem::{size_of, zeroed};
+
+    // Returns the offset of a field, given pointers to the struct and field.
+    fn offset<T, F>(base: &T, field: &F) -> usize {
+        (field as *const F as usize) - (base as *const T as usize)
+    }
+
+    #[test]
+    fn test_vie_layout() {
+        let vie: vie = unsafe { zeroed() };
+        assert_eq!(size_of::<vie>(), 64);
+        assert_eq!(offset(&vie, &vie.num_valid), 15);
+        assert_eq!(offset(&vie, &vie.bitfields), 17);
+        assert_eq!(offset(&vie, &vie.disp_bytes), 22);
+        assert_eq!(offset(&vie, &vie.scale), 24);
+        assert_eq!(offset(&vie, &vie.base_register), 28);
+        assert_eq!(offset(&vie, &vie.segment_register), 36);
+        assert_eq!(offset(&vie, &vie.displacement), 40);
+        assert_eq!(offset(&vie, &vie.immediate), 48);
+        assert_eq!(offset(&vie, &vie.decoded), 56);
+        assert_eq!(offset(&vie, &vie.op), 58);
+
+        let inst_emul: vm_exit_inst_emul = unsafe { zeroed() };
+        assert_eq!(size_of::<vm_exit_inst_emul>(), 120);
+        assert_eq!(offset(&inst_emul, &inst_emul.paging), 32);
+        assert_eq!(offset(&inst_emul, &inst_emul.vie), 56);
+    }
+
+    #[test]
+    fn test_vie_bitfields() {
+        let mut vie: vie = unsafe { zeroed() };
+
+        // mov %eax, 0x8(%rcx,%rdx,4) with a 64-bit address size
+        vie.set_addrsize(8);
+        vie.set_opsize(4);
+        vie.set_modrm_mod(1);
+        vie.set_modrm_reg(0);
+        vie.set_modrm_rm(4);
+        vie.set_sib_ss(2);
+        vie.set_sib_index(2);
+        vie.set_sib_base(1);
+        assert_eq!(vie.bitfields, [0x48, 0x00, 0x04, 0x24, 0x12]);
+
+        vie.bitfields = [0x00, 0x19, 0xf2, 0x00, 0x00];
+        assert_eq!(vie.rex_w(), true);
+        assert_eq!(vie.rex_b(), true);
+        assert_eq!(vie.rex_present(), true);
+        assert_eq!(vie.repz_present(), false);
+        assert_eq!(vie.segment_override(), true);
+        assert_eq!(vie.addrsize_override(), false);
+        assert_eq!(vie.modrm_reg(), 0xf);
+        assert_eq!(vie.modrm_mod(), 0);
+    }
+
+    #[test]
+    fn test_vm_inout_layout() {
+        let mut inout: vm_inout = unsafe { zeroed() };
+        assert_eq!(size_of::<vm_inout>(), 8);
+        assert_eq!(offset(&inout, &inout.port), 2);
+        assert_eq!(offset(&inout, &inout.eax), 4);
+
+        // rep outsw
+        inout.bitfields = [0x32, 0x00];
+        assert_eq!(inout.bytes(), 2);
+        assert_eq!(inout.is_in(), false);
+        assert_eq!(inout.is_string(), true);
+        assert_eq!(inout.is_repeat(), true);
+
+        inout.set_in(true);
+        inout.set_bytes(4);
+        assert_eq!(inout.bitfields, [0x3c, 0x00]);
+    }
+}
@@ -3,7 +3,7 @@
 //! These are defined in Rust, but mimic the C constants and structs
 //! defined in `machine/vmm_dev.h`, `sys/ioccom.h`, and `sys/time.h`.
 
-use std::os::raw::{c_int, c_uint, c_long, c_longlong, c_ulonglong, c_char};
+use std::os::raw::{c_int, c_uint, c_long, c_ulong, c_longlong, c_ulonglong, c_char};
 use std::mem::size_of;
 use libc::{size_t};
 
@@ -12,6 +12,7 @@ use crate::include::vmm::*;
 // Define const from sys/param.h
 
 const SPECNAMELEN: usize = 255; // max length of devicename
+pub const NBBY: usize = 8;         // number of bits in a byte
 
 // Define struct from sys/time.h
 
@@ -138,6 +139,12 @@ enum IocNum {
         IOCNUM_WRLOCK_CYCLE = 257,
 }
 
+// ABI versions whose struct layouts match the definitions in this file,
+// such as the 0x90 byte vm_run checked in test_ioctl_general. Versions with
+// other layouts can only be added along with the matching structs.
+pub const VMM_ABI_VERSIONS: &[c_int] = &[1];
+
+pub const VM_ABIVERS: c_int = define_ioctl_op!(IOC_OUT, IocNum::IOCNUM_ABIVERS as c_uint, (size_of::<c_int>() as c_uint));
 pub const VM_RUN: c_int = define_ioctl_op!(IOC_INOUT, IocNum::IOCNUM_RUN as c_uint, (size_of::<vm_run>() as c_uint));
 pub const VM_SUSPEND: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_SUSPEND as c_uint, (size_of::<vm_suspend>() as c_uint));
 pub const VM_REINIT: c_int = define_ioctl_op!(IOC_VOID, IocNum::IOCNUM_REINIT as c_uint, 0);
@@ -149,10 +156,22 @@ pub const VM_MMAP_MEMSEG: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_MMAP_M
 pub const VM_MMAP_GETNEXT: c_int = define_ioctl_op!(IOC_INOUT, IocNum::IOCNUM_MMAP_GETNEXT as c_uint, (size_of::<vm_memmap>() as c_uint));
 pub const VM_MUNMAP_MEMSEG: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_MUNMAP_MEMSEG as c_uint, (size_of::<vm_munmap>() as c_uint));
 
+pub const VM_GLA2GPA: c_int = define_ioctl_op!(IOC_INOUT, IocNum::IOCNUM_GLA2GPA as c_uint, (size_of::<vm_gla2gpa>() as c_uint));
+pub const VM_GLA2GPA_NOFAULT: c_int = define_ioctl_op!(IOC_INOUT, IocNum::IOCNUM_GLA2GPA_NOFAULT as c_uint, (size_of::<vm_gla2gpa>() as c_uint));
+
 pub const VM_SET_REGISTER: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_SET_REGISTER as c_uint, (size_of::<vm_register>() as c_uint));
 pub const VM_GET_REGISTER: c_int = define_ioctl_op!(IOC_INOUT, IocNum::IOCNUM_GET_REGISTER as c_uint, (size_of::<vm_register>() as c_uint));
 pub const VM_SET_SEGMENT_DESCRIPTOR: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_SET_SEGMENT_DESCRIPTOR as c_uint, (size_of::<vm_seg_desc>() as c_uint));
 pub const VM_GET_SEGMENT_DESCRIPTOR: c_int = define_ioctl_op!(IOC_INOUT, IocNum::IOCNUM_GET_SEGMENT_DESCRIPTOR as c_uint, (size_of::<vm_seg_desc>() as c_uint));
+pub const VM_SET_REGISTER_SET: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_SET_REGISTER_SET as c_uint, (size_of::<vm_register_set>() as c_uint));
+pub const VM_GET_REGISTER_SET: c_int = define_ioctl_op!(IOC_INOUT, IocNum::IOCNUM_GET_REGISTER_SET as c_uint, (size_of::<vm_register_set>() as c_uint));
+
+pub const VM_BIND_PPTDEV: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_BIND_PPTDEV as c_uint, (size_of::<vm_pptdev>() as c_uint));
+pub const VM_UNBIND_PPTDEV: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_UNBIND_PPTDEV as c_uint, (size_of::<vm_pptdev>() as c_uint));
+pub const VM_MAP_PPTDEV_MMIO: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_MAP_PPTDEV_MMIO as c_uint, (size_of::<vm_pptdev_mmio>() as c_uint));
+pub const VM_PPTDEV_MSI: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_PPTDEV_MSI as c_uint, (size_of::<vm_pptdev_msi>() as c_uint));
+pub const VM_PPTDEV_MSIX: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_PPTDEV_MSIX as c_uint, (size_of::<vm_pptdev_msix>() as c_uint));
+pub const VM_GET_PPTDEV_LIMITS: c_int = define_ioctl_op!(IOC_INOUT, IocNum::IOCNUM_GET_PPTDEV_LIMITS as c_uint, (size_of::<vm_pptdev_limits>() as c_uint));
 
 pub const VM_SET_CAPABILITY: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_SET_CAPABILITY as c_uint, (size_of::<vm_capability>() as c_uint));
 pub const VM_GET_CAPABILITY: c_int = define_ioctl_op!(IOC_INOUT, IocNum::IOCNUM_GET_CAPABILITY as c_uint, (size_of::<vm_capability>() as c_uint));
@@ -160,13 +179,17 @@ pub const VM_GET_CAPABILITY: c_int = define_ioctl_op!(IOC_INOUT, IocNum::IOCNUM_
 pub const VM_SET_X2APIC_STATE: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_SET_X2APIC_STATE as c_uint, (size_of::<vm_x2apic>() as c_uint));
 pub const VM_GET_X2APIC_STATE: c_int = define_ioctl_op!(IOC_INOUT, IocNum::IOCNUM_GET_X2APIC_STATE as c_uint, (size_of::<vm_x2apic>() as c_uint));
 
+pub const VM_GET_HPET_CAPABILITIES: c_int = define_ioctl_op!(IOC_OUT, IocNum::IOCNUM_GET_HPET_CAPABILITIES as c_uint, (size_of::<vm_hpet_cap>() as c_uint));
+
 pub const VM_SET_TOPOLOGY: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_SET_TOPOLOGY as c_uint, (size_of::<vm_cpu_topology>() as c_uint));
 pub const VM_GET_TOPOLOGY: c_int = define_ioctl_op!(IOC_OUT, IocNum::IOCNUM_GET_TOPOLOGY as c_uint, (size_of::<vm_cpu_topology>() as c_uint));
 pub const VM_STATS_IOC: c_int = define_ioctl_op!(IOC_INOUT, IocNum::IOCNUM_VM_STATS as c_uint, (size_of::<vm_stats>() as c_uint));
+pub const VM_STAT_DESC: c_int = define_ioctl_op!(IOC_INOUT, IocNum::IOCNUM_VM_STAT_DESC as c_uint, (size_of::<vm_stat_desc>() as c_uint));
 
 
 pub const VM_ACTIVATE_CPU: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_ACTIVATE_CPU as c_uint, (size_of::<vm_activate_cpu>() as c_uint));
 pub const VM_SUSPEND_CPU: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_SUSPEND_CPU as c_uint, (size_of::<vm_activate_cpu>() as c_uint));
+pub const VM_GET_CPUSET: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_GET_CPUSET as c_uint, (size_of::<vm_cpuset>() as c_uint));
 pub const VM_RESUME_CPU: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_RESUME_CPU as c_uint, (size_of::<vm_activate_cpu>() as c_uint));
 
 pub const VM_RTC_WRITE: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_RTC_WRITE as c_uint, (size_of::<vm_rtc_data>() as c_uint));
@@ -184,10 +207,15 @@ pub const VM_LAPIC_MSI: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_LAPIC_MS
 pub const VM_IOAPIC_ASSERT_IRQ: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_IOAPIC_ASSERT_IRQ as c_uint, (size_of::<vm_ioapic_irq>() as c_uint));
 pub const VM_IOAPIC_DEASSERT_IRQ: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_IOAPIC_DEASSERT_IRQ as c_uint, (size_of::<vm_ioapic_irq>() as c_uint));
 pub const VM_IOAPIC_PULSE_IRQ: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_IOAPIC_PULSE_IRQ as c_uint, (size_of::<vm_ioapic_irq>() as c_uint));
+pub const VM_ISA_ASSERT_IRQ: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_ISA_ASSERT_IRQ as c_uint, (size_of::<vm_isa_irq>() as c_uint));
+pub const VM_ISA_DEASSERT_IRQ: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_ISA_DEASSERT_IRQ as c_uint, (size_of::<vm_isa_irq>() as c_uint));
+pub const VM_ISA_PULSE_IRQ: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_ISA_PULSE_IRQ as c_uint, (size_of::<vm_isa_irq>() as c_uint));
+pub const VM_ISA_SET_IRQ_TRIGGER: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_ISA_SET_IRQ_TRIGGER as c_uint, (size_of::<vm_isa_irq_trigger>() as c_uint));
 pub const VM_IOAPIC_PINCOUNT: c_int = define_ioctl_op!(IOC_OUT, IocNum::IOCNUM_IOAPIC_PINCOUNT as c_uint, (size_of::<c_int>() as c_uint));
 pub const VM_RESTART_INSTRUCTION: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_RESTART_INSTRUCTION as c_uint, (size_of::<c_int>() as c_uint));
 
 pub const VM_DEVMEM_GETOFFSET: c_int = define_ioctl_op!(IOC_IN, IocNum::IOCNUM_DEVMEM_GETOFFSET as c_uint, (size_of::<vm_devmem_offset>() as c_uint));
+pub const VM_WRLOCK_CYCLE: c_int = define_ioctl_op!(IOC_VOID, IocNum::IOCNUM_WRLOCK_CYCLE as c_uint, 0);
 
 
 // ioctls used against ctl device for vm create/destroy
@@ -241,6 +269,18 @@ impl Default for vm_memseg {
     }
 }
 
+// For VM_GLA2GPA and VM_GLA2GPA_NOFAULT
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct vm_gla2gpa {
+    pub vcpuid: c_int,              // inputs
+    pub prot: c_int,                // PROT_READ or PROT_WRITE
+    pub gla: c_ulonglong,
+    pub paging: vm_guest_paging,
+    pub fault: c_int,               // outputs
+    pub gpa: c_ulonglong,
+}
+
 // For VM_RTC_SETTIME and VM_RTC_GETTIME
 #[repr(C)]
 #[derive(Copy, Clone, Default)]
@@ -283,6 +323,17 @@ pub struct vm_seg_desc {
     pub desc: seg_desc,     // struct seg_desc
 }
 
+// For VM_SET_REGISTER_SET and VM_GET_REGISTER_SET
+// The 'regnums' and 'regvals' arrays must each hold 'count' entries.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct vm_register_set {
+    pub cpuid: c_int,
+    pub count: c_uint,
+    pub regnums: *const c_int,          // enum vm_reg_name
+    pub regvals: *mut c_ulonglong,
+}
+
 // For VM_RUN
 #[repr(C)]
 #[derive(Copy, Clone, Default)]
@@ -329,6 +380,62 @@ impl Default for vm_x2apic {
     }
 }
 
+// For VM_BIND_PPTDEV and VM_UNBIND_PPTDEV
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_pptdev {
+    pub pptfd: c_int,
+}
+
+// For VM_MAP_PPTDEV_MMIO
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_pptdev_mmio {
+    pub pptfd: c_int,
+    pub gpa: c_ulonglong,       // vm_paddr_t
+    pub hpa: c_ulonglong,       // vm_paddr_t
+    pub len: size_t,
+}
+
+// For VM_PPTDEV_MSI
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_pptdev_msi {
+    pub vcpu: c_int,
+    pub pptfd: c_int,
+    pub numvec: c_int,          // 0 means disabled
+    pub msg: c_ulonglong,
+    pub addr: c_ulonglong,
+}
+
+// For VM_PPTDEV_MSIX
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_pptdev_msix {
+    pub vcpu: c_int,
+    pub pptfd: c_int,
+    pub idx: c_int,
+    pub msg: c_ulonglong,
+    pub vector_control: c_uint,
+    pub addr: c_ulonglong,
+}
+
+// For VM_GET_PPTDEV_LIMITS
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_pptdev_limits {
+    pub pptfd: c_int,
+    pub msi_limit: c_int,
+    pub msix_limit: c_int,
+}
+
+// For VM_GET_HPET_CAPABILITIES
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_hpet_cap {
+    pub capabilities: u32,      // lower 32 bits of HPET capabilities
+}
+
 // For VM_SUSPEND
 #[repr(C)]
 #[derive(Copy, Clone)]
@@ -343,6 +450,20 @@ pub struct vm_activate_cpu {
     pub vcpuid: c_int,
 }
 
+// For VM_GET_CPUSET
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct vm_cpuset {
+    pub which: c_int,
+    pub cpusetsize: c_int,
+    pub cpus: *mut c_ulong,     // cpuset_t
+}
+
+// Values for the 'which' field of vm_cpuset
+pub const VM_ACTIVE_CPUS: c_int = 0;
+pub const VM_SUSPENDED_CPUS: c_int = 1;
+pub const VM_DEBUG_CPUS: c_int = 2;
+
 // For VM_SET_TOPOLOGY and VM_GET_TOPOLOGY
 #[repr(C)]
 #[derive(Copy, Clone, Default)]
@@ -376,6 +497,25 @@ impl Default for vm_stats {
     }
 }
 
+const VM_STAT_DESC_LEN: usize = 128;
+
+// For VM_STAT_DESC
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct vm_stat_desc {
+    pub index: c_int,                       // in
+    pub desc: [c_char; VM_STAT_DESC_LEN],   // out
+}
+
+impl Default for vm_stat_desc {
+    fn default() -> vm_stat_desc {
+        vm_stat_desc {
+            index: 0,
+            desc: [0 as c_char; VM_STAT_DESC_LEN],
+        }
+    }
+}
+
 // For VM_SET_INTINFO and VM_GET_INTINFO
 #[repr(C)]
 #[derive(Copy, Clone, Default)]
@@ -426,6 +566,22 @@ pub struct vm_ioapic_irq {
     pub irq: c_int,
 }
 
+// For VM_ISA_ASSERT_IRQ, VM_ISA_DEASSERT_IRQ, and VM_ISA_PULSE_IRQ
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_isa_irq {
+    pub atpic_irq: c_int,
+    pub ioapic_irq: c_int,
+}
+
+// For VM_ISA_SET_IRQ_TRIGGER
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct vm_isa_irq_trigger {
+    pub atpic_irq: c_int,
+    pub trigger: vm_intr_trigger,  // enum vm_intr_trigger
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -435,6 +591,13 @@ mod tests {
     fn test_ioctl_stats() {
         assert_eq!(size_of::<vm_stats>(), 0x318);
         assert_eq!(VM_STATS_IOC as u32, 0xc0187632);
+        assert_eq!(size_of::<vm_stat_desc>(), 0x84);
+        assert_eq!(VM_STAT_DESC as u32, 0xc0847633);
+    }
+
+    #[test]
+    fn test_ioctl_abivers() {
+        assert_eq!(VM_ABIVERS as u32, 0x40047600);
     }
 
     #[test]
@@ -448,9 +611,55 @@ mod tests {
         assert_eq!(VM_REINIT as u32, 0x20007605);
     }
 
+    #[test]
+    fn test_ioctl_registers() {
+        assert_eq!(size_of::<vm_register>(), 0x10);
+        assert_eq!(size_of::<vm_register_set>(), 0x18);
+        assert_eq!(VM_SET_REGISTER as u32, 0x80107614);
+        assert_eq!(VM_GET_REGISTER as u32, 0xc0107615);
+        assert_eq!(VM_SET_REGISTER_SET as u32, 0x80187618);
+        assert_eq!(VM_GET_REGISTER_SET as u32, 0xc0187619);
+    }
+
+    #[test]
+    fn test_ioctl_hpet() {
+        assert_eq!(size_of::<vm_hpet_cap>(), 4);
+        assert_eq!(VM_GET_HPET_CAPABILITIES as u32, 0x4004763e);
+    }
+
+    #[test]
+    fn test_ioctl_isa_irq() {
+        assert_eq!(size_of::<vm_isa_irq>(), 8);
+        assert_eq!(size_of::<vm_isa_irq_trigger>(), 8);
+        assert_eq!(VM_ISA_ASSERT_IRQ as u32, 0x80087650);
+        assert_eq!(VM_ISA_DEASSERT_IRQ as u32, 0x80087651);
+        assert_eq!(VM_ISA_PULSE_IRQ as u32, 0x80087652);
+        assert_eq!(VM_ISA_SET_IRQ_TRIGGER as u32, 0x80087653);
+    }
+
+    #[test]
+    fn test_ioctl_pptdev() {
+        assert_eq!(size_of::<vm_pptdev>(), 4);
+        assert_eq!(size_of::<vm_pptdev_mmio>(), 0x20);
+        assert_eq!(size_of::<vm_pptdev_msi>(), 0x20);
+        assert_eq!(size_of::<vm_pptdev_msix>(), 0x28);
+        assert_eq!(size_of::<vm_pptdev_limits>(), 0xc);
+        assert_eq!(VM_BIND_PPTDEV as u32, 0x80047628);
+        assert_eq!(VM_UNBIND_PPTDEV as u32, 0x80047629);
+        assert_eq!(VM_MAP_PPTDEV_MMIO as u32, 0x8020762a);
+        assert_eq!(VM_PPTDEV_MSI as u32, 0x8020762b);
+        assert_eq!(VM_PPTDEV_MSIX as u32, 0x8028762c);
+        assert_eq!(VM_GET_PPTDEV_LIMITS as u32, 0xc00c762d);
+    }
+
     #[test]
     fn test_ioctl_topology() {
         assert_eq!(size_of::<vm_activate_cpu>(), 4);
+        assert_eq!(size_of::<vm_cpuset>(), 0x10);
+        assert_eq!(VM_ACTIVATE_CPU as u32, 0x8004765a);
+        assert_eq!(VM_GET_CPUSET as u32, 0x8010765b);
+        assert_eq!(VM_SUSPEND_CPU as u32, 0x8004765c);
+        assert_eq!(VM_RESUME_CPU as u32, 0x8004765d);
         assert_eq!(size_of::<vm_cpu_topology>(), 8);
         assert_eq!(VM_SET_TOPOLOGY as u32, 0x8008763f);
         assert_eq!(VM_GET_TOPOLOGY as u32, 0x40087640);
@@ -464,4 +673,17 @@ mod tests {
         assert_eq!(VM_MMAP_MEMSEG as u32, 0x80287610);
         assert_eq!(VM_MMAP_GETNEXT as u32, 0xc0287611);
     }
+
+    #[test]
+    fn test_ioctl_wrlock_cycle() {
+        assert_eq!(VM_WRLOCK_CYCLE as u32, 0x20007701);
+    }
+
+    #[test]
+    fn test_ioctl_gla2gpa() {
+        assert_eq!(size_of::<vm_guest_paging>(), 0x18);
+        assert_eq!(size_of::<vm_gla2gpa>(), 0x38);
+        assert_eq!(VM_GLA2GPA as u32, 0xc038760d);
+        assert_eq!(VM_GLA2GPA_NOFAULT as u32, 0xc0387612);
+    }
 }
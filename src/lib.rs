@@ -15,8 +15,55 @@
 //! and maintainability, and simplifies reasoning from a security
 //! perspective.
 
+#[macro_use]
+mod macros;
+
+pub mod decode;
+pub mod emulate;
+pub mod paging;
+pub mod segment;
+pub mod svm;
 pub mod system;
 pub mod vm;
+pub mod vmx;
 mod include;
 
+use std::fmt;
+
 pub use vmm_sys_util::errno::Error;
+
+/// Errors returned when opening a handle to the VMM system or to a virtual
+/// machine device.
+#[derive(Debug)]
+pub enum OpenError {
+    /// Opening or querying the device failed.
+    Sys(Error),
+    /// The running kernel uses an ABI version whose struct layouts don't
+    /// match the ones compiled into this library.
+    IncompatibleAbi {
+        found: i32,
+        supported: &'static [i32],
+    },
+}
+
+impl fmt::Display for OpenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OpenError::Sys(e) => write!(f, "{}", e),
+            OpenError::IncompatibleAbi { found, supported } => {
+                let versions: Vec<String> = supported.iter().map(|v| v.to_string()).collect();
+                write!(f, "kernel bhyve ABI version {} is incompatible with this library (supported versions: {})",
+                       found, versions.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for OpenError {}
+
+impl From<Error> for OpenError {
+    fn from(e: Error) -> OpenError {
+        OpenError::Sys(e)
+    }
+}
+
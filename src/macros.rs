@@ -0,0 +1,156 @@
+// Macros shared by the modules of the crate.
+
+// Defines a public enum for a table of architectural codes, such as exit
+// reasons. Each listed variant maps to a fixed code, and any other code
+// maps to an 'Unknown' variant carrying the raw value, so decoding never
+// fails. Generates 'from_code' and 'code' to convert in both directions.
+//
+// An optional 'ranges' block lists variants that cover a contiguous range
+// of codes, such as one code per control register. These variants carry
+// the offset of the code from the start of the range.
+macro_rules! code_enum {
+    (
+        $(#[$meta:meta])*
+        pub enum $name:ident: $repr:ty {
+            $( $(#[$vmeta:meta])* $variant:ident = $code:expr, )*
+        }
+        $(
+            ranges {
+                $( $(#[$rmeta:meta])* $rvariant:ident = $lo:literal ..= $hi:literal, )*
+            }
+        )?
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Copy, Clone, PartialEq)]
+        pub enum $name {
+            $( $(#[$vmeta])* $variant, )*
+            $( $( $(#[$rmeta])* $rvariant(u8), )* )?
+            /// A code that isn't listed in this table
+            Unknown($repr),
+        }
+
+        impl $name {
+            /// Maps a raw code to its variant.
+            pub fn from_code(code: $repr) -> $name {
+                match code {
+                    $( $code => $name::$variant, )*
+                    $( $( $lo..=$hi => $name::$rvariant((code - $lo) as u8), )* )?
+                    other => $name::Unknown(other),
+                }
+            }
+
+            /// Returns the raw code of the variant.
+            pub fn code(&self) -> $repr {
+                match *self {
+                    $( $name::$variant => $code, )*
+                    $( $( $name::$rvariant(offset) => $lo + offset as $repr, )* )?
+                    $name::Unknown(other) => other,
+                }
+            }
+        }
+    };
+}
+
+// Defines accessors for C bitfields, which Rust can't express directly. The
+// bitfields are stored in a byte array field of a #[repr(C)] struct, in the
+// position the C compiler gives them. Each entry names a getter and a
+// setter, the type of the value, and the position of the bitfield as the
+// number of its least significant bit within the array and its width.
+// Bits are numbered from the least significant bit of the first byte,
+// which matches how the C compiler allocates bitfields on x86.
+//
+//     bitfield! {
+//         vm_inout.bitfields {
+//             pub bytes, set_bytes: u16 [0; 3];
+//         }
+//     }
+macro_rules! bitfield {
+    (
+        $name:ident . $field:ident {
+            $(
+                $(#[$meta:meta])*
+                $vis:vis $get:ident, $set:ident: $ty:ty [$lsb:expr; $width:expr];
+            )*
+        }
+    ) => {
+        impl $name {
+            $(
+                $(#[$meta])*
+                #[allow(dead_code)]
+                $vis fn $get(&self) -> $ty {
+                    let bits = crate::macros::get_bits(&self.$field, $lsb, $width);
+                    return <$ty as crate::macros::BitfieldValue>::from_bits(bits);
+                }
+
+                #[allow(dead_code)]
+                $vis fn $set(&mut self, value: $ty) {
+                    let bits = <$ty as crate::macros::BitfieldValue>::into_bits(value);
+                    crate::macros::set_bits(&mut self.$field, $lsb, $width, bits);
+                }
+            )*
+        }
+    };
+}
+
+// Types that bitfield accessors can return. Values wider than the bitfield
+// are truncated by the setter.
+pub(crate) trait BitfieldValue {
+    fn from_bits(bits: u64) -> Self;
+    fn into_bits(self) -> u64;
+}
+
+impl BitfieldValue for bool {
+    fn from_bits(bits: u64) -> bool {
+        bits != 0
+    }
+
+    fn into_bits(self) -> u64 {
+        self as u64
+    }
+}
+
+impl BitfieldValue for u8 {
+    fn from_bits(bits: u64) -> u8 {
+        bits as u8
+    }
+
+    fn into_bits(self) -> u64 {
+        self as u64
+    }
+}
+
+impl BitfieldValue for u16 {
+    fn from_bits(bits: u64) -> u16 {
+        bits as u16
+    }
+
+    fn into_bits(self) -> u64 {
+        self as u64
+    }
+}
+
+// Reads 'width' bits starting at bit 'lsb' of 'bytes'.
+pub(crate) fn get_bits(bytes: &[u8], lsb: usize, width: usize) -> u64 {
+    let mut bits: u64 = 0;
+    for i in 0..width {
+        let bit = lsb + i;
+        if (bytes[bit / 8] & (1 << (bit % 8))) != 0 {
+            bits |= 1 << i;
+        }
+    }
+    return bits;
+}
+
+// Writes the low 'width' bits of 'bits' starting at bit 'lsb' of 'bytes',
+// leaving the surrounding bits unchanged.
+pub(crate) fn set_bits(bytes: &mut [u8], lsb: usize, width: usize, bits: u64) {
+    for i in 0..width {
+        let bit = lsb + i;
+        let mask = 1 << (bit % 8);
+        if (bits & (1 << i)) != 0 {
+            bytes[bit / 8] |= mask;
+        } else {
+            bytes[bit / 8] &= !mask;
+        }
+    }
+}
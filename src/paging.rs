@@ -0,0 +1,592 @@
+//! Software walker for guest page tables.
+//!
+//! Translates guest linear addresses to guest physical addresses by reading
+//! the guest page tables through the `GuestMemory` trait, without the
+//! kernel's `gla2gpa`. This works on any source of guest physical memory,
+//! such as a running `VirtualMachine`, a snapshot, or a core file.
+
+use std::error;
+use std::fmt;
+
+use crate::include::pte::{PG_V, PG_RW, PG_U, PG_A, PG_M, PG_PS, PG_NX, PG_FRAME, PG_FRAME32};
+use crate::include::pte::{PGEX_P, PGEX_W, PGEX_U, PGEX_RSV, PGEX_I};
+use crate::vm::{vm_guest_paging, vm_paging_mode, FaultType, VirtualMachine};
+use crate::Error;
+
+const GB: u64 = 1 << 30;
+
+// Reserved bits of the PAE page directory pointer table entries.
+const PDPTE_RSVD: u64 = PG_NX | 0x1e6;
+
+// Bits of a 4MB page entry in 32-bit paging that hold address bits 39:32.
+const PSE36_HIGH: u64 = 0xff << 13;
+
+/// Guest physical memory, as read and written by the page table walker.
+pub trait GuestMemory {
+    /// Reads the guest physical memory at 'gpa' into 'buf'.
+    fn read_phys(&self, gpa: u64, buf: &mut [u8]) -> Result<(), Error>;
+
+    /// Writes 'buf' to the guest physical memory at 'gpa'.
+    fn write_phys(&self, gpa: u64, buf: &[u8]) -> Result<(), Error>;
+}
+
+impl GuestMemory for VirtualMachine {
+    fn read_phys(&self, gpa: u64, buf: &mut [u8]) -> Result<(), Error> {
+        self.read_guest(gpa, buf)?;
+        Ok(())
+    }
+
+    fn write_phys(&self, gpa: u64, buf: &[u8]) -> Result<(), Error> {
+        self.write_guest(gpa, buf)?;
+        Ok(())
+    }
+}
+
+/// Errors returned by `translate`.
+#[derive(Debug)]
+pub enum WalkError {
+    /// The access would cause a page fault (#PF) with this error code.
+    Fault { error_code: u32 },
+    /// The linear address isn't canonical, which would cause a general
+    /// protection fault (#GP), or a stack fault (#SS) for stack accesses.
+    NonCanonical,
+    /// Reading or writing a page table failed.
+    Memory(Error),
+}
+
+impl fmt::Display for WalkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WalkError::Fault { error_code } => write!(f, "page fault, error code {:#x}", error_code),
+            WalkError::NonCanonical => write!(f, "non-canonical linear address"),
+            WalkError::Memory(e) => write!(f, "failed to access page table: {}", e),
+        }
+    }
+}
+
+impl error::Error for WalkError {}
+
+impl From<Error> for WalkError {
+    fn from(e: Error) -> WalkError {
+        WalkError::Memory(e)
+    }
+}
+
+/// Guest paging controls that aren't part of `vm_guest_paging`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct WalkOptions {
+    /// EFER.NXE, the NX bit prevents instruction fetches. If not set, the
+    /// NX bit is reserved.
+    pub nxe: bool,
+    /// CR0.WP, supervisor mode writes honour the R/W bit.
+    pub wp: bool,
+    /// CR4.PSE, 4MB pages are enabled in 32-bit paging.
+    pub pse: bool,
+    /// CR4.LA57, 5-level paging is used in `PAGING_MODE_64`.
+    pub la57: bool,
+    /// MAXPHYADDR, the physical address width from CPUID leaf 0x80000008.
+    /// Address bits of entries at and above it are reserved.
+    pub maxphyaddr: u8,
+    /// Set the accessed and dirty bits of the entries used, as the MMU
+    /// does. The updates aren't atomic with respect to running VCPUs.
+    pub set_accessed_dirty: bool,
+}
+
+impl Default for WalkOptions {
+    /// The controls used by most guests, without updating the page tables.
+    fn default() -> WalkOptions {
+        WalkOptions {
+            nxe: true,
+            wp: true,
+            pse: true,
+            la57: false,
+            maxphyaddr: 52,
+            set_accessed_dirty: false,
+        }
+    }
+}
+
+/// A translated linear address.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PageMapping {
+    /// Guest physical address
+    pub gpa: u64,
+    /// Size of the page that maps the address, in bytes
+    pub page_size: u64,
+}
+
+/// Translates the guest linear address 'gla' for an access of type 'access'
+/// with the guest paging state 'paging', by walking the page tables in
+/// 'mem'. Accesses are in user mode if 'paging.cpl' is 3.
+///
+/// Entries with reserved bits set fault with `PGEX_RSV`, as they do on the
+/// processor. With the default 'maxphyaddr' of 52, the architectural
+/// maximum, address bits aren't reserved, so it should be set from the
+/// guest CPUID for an exact match. Protection keys, SMEP, SMAP and the
+/// reserved bits of CR3 aren't checked.
+pub fn translate(mem: &dyn GuestMemory, paging: &vm_guest_paging, gla: u64, access: FaultType,
+                 options: &WalkOptions) -> Result<PageMapping, WalkError> {
+    let walker = Walker {
+        mem: mem,
+        options: options,
+        user: paging.cpl == 3,
+        write: access == FaultType::Write,
+        fetch: access == FaultType::Execute,
+        has_nx: paging.paging_mode != vm_paging_mode::PAGING_MODE_32,
+    };
+
+    match paging.paging_mode {
+        vm_paging_mode::PAGING_MODE_FLAT => {
+            return Ok(PageMapping { gpa: gla, page_size: 1 << 12 });
+        }
+        vm_paging_mode::PAGING_MODE_32 => {
+            return walker.walk(paging.cr3 & PG_FRAME32, gla & 0xffff_ffff, 2);
+        }
+        vm_paging_mode::PAGING_MODE_PAE => {
+            // The four PDPTEs only have a present bit
+            let gla = gla & 0xffff_ffff;
+            let pdpte = walker.read_entry((paging.cr3 & 0xffff_ffe0) + ((gla >> 30) & 0x3) * 8)?;
+            if (pdpte & PG_V) == 0 {
+                return Err(walker.fault(false, false));
+            }
+            if (pdpte & (PDPTE_RSVD | walker.address_rsvd())) != 0 {
+                return Err(walker.fault(true, true));
+            }
+            return walker.walk(pdpte & PG_FRAME, gla, 2);
+        }
+        vm_paging_mode::PAGING_MODE_64 => {
+            let levels = if options.la57 { 5 } else { 4 };
+            // Bits above the highest translated bit must be copies of it
+            let bits = 12 + 9 * levels;
+            let upper = (gla as i64) >> (bits - 1);
+            if upper != 0 && upper != -1 {
+                return Err(WalkError::NonCanonical);
+            }
+            return walker.walk(paging.cr3 & PG_FRAME, gla, levels);
+        }
+    }
+}
+
+struct Walker<'a> {
+    mem: &'a dyn GuestMemory,
+    options: &'a WalkOptions,
+    user: bool,
+    write: bool,
+    fetch: bool,
+    // 32-bit paging has 4-byte entries, without an NX bit
+    has_nx: bool,
+}
+
+impl<'a> Walker<'a> {
+    fn entry_size(&self) -> u64 {
+        if self.has_nx { 8 } else { 4 }
+    }
+
+    fn read_entry(&self, addr: u64) -> Result<u64, WalkError> {
+        let mut buf = [0u8; 8];
+        self.mem.read_phys(addr, &mut buf[..self.entry_size() as usize])?;
+        return Ok(u64::from_le_bytes(buf));
+    }
+
+    fn write_entry(&self, addr: u64, entry: u64) -> Result<(), WalkError> {
+        self.mem.write_phys(addr, &entry.to_le_bytes()[..self.entry_size() as usize])?;
+        return Ok(());
+    }
+
+    fn fault(&self, present: bool, reserved: bool) -> WalkError {
+        let mut error_code = 0;
+        if present {
+            error_code |= PGEX_P;
+        }
+        if self.write {
+            error_code |= PGEX_W;
+        }
+        if self.user {
+            error_code |= PGEX_U;
+        }
+        if reserved {
+            error_code |= PGEX_RSV;
+        }
+        if self.fetch && self.has_nx && self.options.nxe {
+            error_code |= PGEX_I;
+        }
+        return WalkError::Fault { error_code: error_code };
+    }
+
+    // Returns the bits of 64-bit entries that are reserved because they
+    // are above MAXPHYADDR.
+    fn address_rsvd(&self) -> u64 {
+        let width = self.options.maxphyaddr.min(52) as u64;
+        return PG_FRAME & !((1 << width) - 1);
+    }
+
+    // Returns the reserved bits of a present entry that maps 'page_size'
+    // bytes, as a leaf if 'leaf' is set.
+    fn reserved_bits(&self, page_size: u64, leaf: bool) -> u64 {
+        if !self.has_nx {
+            // With PSE-36, bits 20:13 of a 4MB page entry hold address bits
+            // 39:32, and bit 21 is reserved
+            if leaf && page_size > 0x1000 {
+                let width = self.options.maxphyaddr.clamp(32, 40) as u64;
+                let high = PSE36_HIGH & !(((1 << (width - 32)) - 1) << 13);
+                return high | (1 << 21);
+            }
+            return 0;
+        }
+        let mut reserved = self.address_rsvd();
+        if !self.options.nxe {
+            reserved |= PG_NX;
+        }
+        // Large pages are aligned, and bit 12 is the PAT bit
+        if leaf && page_size > 0x1000 {
+            reserved |= (page_size - 1) & !0x1fff;
+        }
+        return reserved;
+    }
+
+    // Walks 'levels' levels of page tables, starting with the table at
+    // 'table'. The access rights are the most restrictive of all the
+    // entries used, and are checked once the walk reaches the page.
+    fn walk(&self, table: u64, gla: u64, levels: u32) -> Result<PageMapping, WalkError> {
+        let (bits, frame_mask) = if self.has_nx { (9, PG_FRAME) } else { (10, PG_FRAME32) };
+        let mut table = table;
+        let mut user = true;
+        let mut writable = true;
+        let mut executable = true;
+        for level in (0..levels).rev() {
+            let shift = 12 + level * bits;
+            let index = (gla >> shift) & ((1 << bits) - 1);
+            let addr = table + index * self.entry_size();
+            let entry = self.read_entry(addr)?;
+            if (entry & PG_V) == 0 {
+                return Err(self.fault(false, false));
+            }
+
+            let page_size: u64 = 1 << shift;
+            let large = level > 0 && (entry & PG_PS) != 0 && (self.has_nx || self.options.pse);
+            let leaf = level == 0 || large;
+            // Large pages are at most 1GB, so PS is reserved in PML4Es and
+            // PML5Es
+            if (entry & self.reserved_bits(page_size, leaf)) != 0 || (large && page_size > GB) {
+                return Err(self.fault(true, true));
+            }
+
+            user &= (entry & PG_U) != 0;
+            writable &= (entry & PG_RW) != 0;
+            executable &= !self.has_nx || (entry & PG_NX) == 0;
+
+            if !leaf {
+                self.set_accessed_dirty(addr, entry, false)?;
+                table = entry & frame_mask;
+                continue;
+            }
+
+            if self.user && !user {
+                return Err(self.fault(true, false));
+            }
+            if self.write && !writable && (self.user || self.options.wp) {
+                return Err(self.fault(true, false));
+            }
+            if self.fetch && self.options.nxe && !executable {
+                return Err(self.fault(true, false));
+            }
+            self.set_accessed_dirty(addr, entry, self.write)?;
+
+            let base = if !self.has_nx && large {
+                (entry & 0xffc0_0000) | (((entry & PSE36_HIGH) >> 13) << 32)
+            } else {
+                entry & frame_mask & !(page_size - 1)
+            };
+            return Ok(PageMapping {
+                gpa: base | (gla & (page_size - 1)),
+                page_size: page_size,
+            });
+        }
+        // The last level is always a leaf
+        unreachable!();
+    }
+
+    // Sets the accessed bit of the entry at 'addr', and the dirty bit if
+    // 'dirty' is set, if the walk updates the page tables.
+    fn set_accessed_dirty(&self, addr: u64, entry: u64, dirty: bool) -> Result<(), WalkError> {
+        if !self.options.set_accessed_dirty {
+            return Ok(());
+        }
+        let mut updated = entry | PG_A;
+        if dirty {
+            updated |= PG_M;
+        }
+        if updated != entry {
+            self.write_entry(addr, updated)?;
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    use crate::paging::*;
+    use crate::vm::vm_cpu_mode;
+
+    // Sparse guest physical memory.
+    #[derive(Default)]
+    struct TestMemory {
+        bytes: RefCell<HashMap<u64, u8>>,
+    }
+
+    impl TestMemory {
+        fn set(&self, gpa: u64, entry: u64, size: usize) {
+            self.write_phys(gpa, &entry.to_le_bytes()[..size]).unwrap();
+        }
+
+        fn get(&self, gpa: u64) -> u64 {
+            let mut buf = [0u8; 8];
+            self.read_phys(gpa, &mut buf).unwrap();
+            u64::from_le_bytes(buf)
+        }
+    }
+
+    impl GuestMemory for TestMemory {
+        fn read_phys(&self, gpa: u64, buf: &mut [u8]) -> Result<(), Error> {
+            let bytes = self.bytes.borrow();
+            for (i, byte) in buf.iter_mut().enumerate() {
+                *byte = *bytes.get(&(gpa + i as u64)).unwrap_or(&0);
+            }
+            Ok(())
+        }
+
+        fn write_phys(&self, gpa: u64, buf: &[u8]) -> Result<(), Error> {
+            let mut bytes = self.bytes.borrow_mut();
+            for (i, byte) in buf.iter().enumerate() {
+                bytes.insert(gpa + i as u64, *byte);
+            }
+            Ok(())
+        }
+    }
+
+    fn paging(mode: vm_paging_mode, cr3: u64, cpl: i32) -> vm_guest_paging {
+        let cpu_mode = match mode {
+            vm_paging_mode::PAGING_MODE_64 => vm_cpu_mode::CPU_MODE_64BIT,
+            _ => vm_cpu_mode::CPU_MODE_PROTECTED,
+        };
+        vm_guest_paging {
+            cr3: cr3,
+            cpl: cpl,
+            cpu_mode: cpu_mode,
+            paging_mode: mode,
+        }
+    }
+
+    fn error_code(result: Result<PageMapping, WalkError>) -> u32 {
+        match result {
+            Err(WalkError::Fault { error_code }) => error_code,
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    const RW_U: u64 = PG_V | PG_RW | PG_U;
+
+    // 4-level tables mapping 0x7f_c020_3000 to 0x5000 as a user page,
+    // 0x4000_0000 with a 2MB page and 0x8000_0000 with a 1GB page.
+    fn tables64(mem: &TestMemory) {
+        // PML4 at 0x1000, PDPT at 0x2000, PD at 0x3000, PT at 0x4000
+        mem.set(0x1000, 0x2000 | RW_U, 8);
+        mem.set(0x2000 + 0x1ff * 8, 0x3000 | RW_U, 8);
+        mem.set(0x3000 + 0x1 * 8, 0x4000 | RW_U, 8);
+        mem.set(0x4000 + 0x3 * 8, 0x5000 | RW_U | PG_NX, 8);
+
+        mem.set(0x2000 + 0x1 * 8, 0x6000 | PG_V | PG_RW, 8);
+        mem.set(0x6000, 0x20_0000 | PG_V | PG_PS, 8);
+        mem.set(0x2000 + 0x2 * 8, 0x4000_0000 | PG_V | PG_RW | PG_PS, 8);
+    }
+
+    #[test]
+    fn test_64bit() {
+        let mem = TestMemory::default();
+        tables64(&mem);
+        let user = paging(vm_paging_mode::PAGING_MODE_64, 0x1000, 3);
+        let kernel = paging(vm_paging_mode::PAGING_MODE_64, 0x1000, 0);
+        let options = WalkOptions::default();
+
+        let mapping = translate(&mem, &user, 0x7f_c020_3abc, FaultType::Write, &options).unwrap();
+        assert_eq!(mapping, PageMapping { gpa: 0x5abc, page_size: 0x1000 });
+
+        let mapping = translate(&mem, &kernel, 0x4012_3456, FaultType::Read, &options).unwrap();
+        assert_eq!(mapping, PageMapping { gpa: 0x32_3456, page_size: 0x20_0000 });
+
+        let mapping = translate(&mem, &kernel, 0xabcd_ef01, FaultType::Write, &options).unwrap();
+        assert_eq!(mapping, PageMapping { gpa: 0x6bcd_ef01, page_size: GB });
+
+        // Not present
+        assert_eq!(error_code(translate(&mem, &kernel, 0x7f_c020_4000, FaultType::Read, &options)), 0);
+
+        // User access to a supervisor page, and a read-only write
+        assert_eq!(error_code(translate(&mem, &user, 0x4000_0000, FaultType::Read, &options)),
+                   PGEX_P | PGEX_U);
+        assert_eq!(error_code(translate(&mem, &kernel, 0x4000_0000, FaultType::Write, &options)),
+                   PGEX_P | PGEX_W);
+
+        // Without CR0.WP, supervisor writes ignore R/W
+        let no_wp = WalkOptions { wp: false, ..options };
+        assert!(translate(&mem, &kernel, 0x4000_0000, FaultType::Write, &no_wp).is_ok());
+
+        // NX prevents fetches, and is reserved without EFER.NXE
+        assert_eq!(error_code(translate(&mem, &user, 0x7f_c020_3000, FaultType::Execute, &options)),
+                   PGEX_P | PGEX_U | PGEX_I);
+        let no_nxe = WalkOptions { nxe: false, ..options };
+        assert_eq!(error_code(translate(&mem, &user, 0x7f_c020_3000, FaultType::Read, &no_nxe)),
+                   PGEX_P | PGEX_U | PGEX_RSV);
+
+        // Non-canonical
+        match translate(&mem, &kernel, 0x0000_8000_0000_0000, FaultType::Read, &options) {
+            Err(WalkError::NonCanonical) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_accessed_dirty() {
+        let mem = TestMemory::default();
+        tables64(&mem);
+        let user = paging(vm_paging_mode::PAGING_MODE_64, 0x1000, 3);
+
+        // Walks don't modify the tables by default, even for writes
+        translate(&mem, &user, 0x7f_c020_3000, FaultType::Write, &WalkOptions::default()).unwrap();
+        assert_eq!(mem.get(0x4018) & (PG_A | PG_M), 0);
+
+        let options = WalkOptions { set_accessed_dirty: true, ..WalkOptions::default() };
+        translate(&mem, &user, 0x7f_c020_3000, FaultType::Read, &options).unwrap();
+        assert_eq!(mem.get(0x1000) & (PG_A | PG_M), PG_A);
+        assert_eq!(mem.get(0x4018) & (PG_A | PG_M), PG_A);
+
+        translate(&mem, &user, 0x7f_c020_3000, FaultType::Write, &options).unwrap();
+        assert_eq!(mem.get(0x3008) & (PG_A | PG_M), PG_A);
+        assert_eq!(mem.get(0x4018) & (PG_A | PG_M), PG_A | PG_M);
+    }
+
+    #[test]
+    fn test_reserved() {
+        let mem = TestMemory::default();
+        tables64(&mem);
+        let kernel = paging(vm_paging_mode::PAGING_MODE_64, 0x1000, 0);
+        let options = WalkOptions { maxphyaddr: 36, ..WalkOptions::default() };
+
+        // Address bits at and above MAXPHYADDR
+        assert!(translate(&mem, &kernel, 0x7f_c020_3000, FaultType::Read, &options).is_ok());
+        mem.set(0x4000 + 0x3 * 8, 0x10_0000_5000 | RW_U, 8);
+        assert_eq!(error_code(translate(&mem, &kernel, 0x7f_c020_3000, FaultType::Read, &options)),
+                   PGEX_P | PGEX_RSV);
+        let mapping = translate(&mem, &kernel, 0x7f_c020_3000, FaultType::Read, &WalkOptions::default()).unwrap();
+        assert_eq!(mapping.gpa, 0x10_0000_5000);
+
+        // Unaligned large pages, although bit 12 is the PAT bit
+        mem.set(0x6000, 0x20_1000 | PG_V | PG_PS, 8);
+        assert!(translate(&mem, &kernel, 0x4000_0000, FaultType::Read, &options).is_ok());
+        mem.set(0x6000, 0x20_2000 | PG_V | PG_PS, 8);
+        assert_eq!(error_code(translate(&mem, &kernel, 0x4000_0000, FaultType::Read, &options)),
+                   PGEX_P | PGEX_RSV);
+        mem.set(0x2000 + 0x2 * 8, 0x4010_0000 | PG_V | PG_PS, 8);
+        assert_eq!(error_code(translate(&mem, &kernel, 0x8000_0000, FaultType::Read, &options)),
+                   PGEX_P | PGEX_RSV);
+
+        // PS in a PML4E
+        mem.set(0x1000, 0x2000 | RW_U | PG_PS, 8);
+        assert_eq!(error_code(translate(&mem, &kernel, 0x7f_c020_3000, FaultType::Write, &options)),
+                   PGEX_P | PGEX_W | PGEX_RSV);
+    }
+
+    #[test]
+    fn test_la57() {
+        let mem = TestMemory::default();
+        // PML5 at 0x1000, pointing to the 4-level tables at 0x2000
+        mem.set(0x1000 + 0x100 * 8, 0x2000 | RW_U, 8);
+        mem.set(0x2000 + 0x1ff * 8, 0x3000 | RW_U, 8);
+        let kernel = paging(vm_paging_mode::PAGING_MODE_64, 0x1000, 0);
+        let options = WalkOptions { la57: true, ..WalkOptions::default() };
+
+        // A 1GB page at 0xff00_ffff_c000_0000
+        mem.set(0x3000 + 0x1ff * 8, 0x4000_0000 | RW_U | PG_PS, 8);
+        let mapping = translate(&mem, &kernel, 0xff00_ffff_c000_1234, FaultType::Read, &options).unwrap();
+        assert_eq!(mapping, PageMapping { gpa: 0x4000_1234, page_size: GB });
+
+        // The same address isn't canonical with 4-level paging
+        match translate(&mem, &kernel, 0xff00_ffff_c000_1234, FaultType::Read, &WalkOptions::default()) {
+            Err(WalkError::NonCanonical) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_32bit() {
+        let mem = TestMemory::default();
+        // PD at 0x1000, PT at 0x2000, mapping 0xc000_1000 to 0x7000, and
+        // 0x0040_0000 with a 4MB page
+        mem.set(0x1000 + 0x300 * 4, 0x2000 | RW_U, 4);
+        mem.set(0x2000 + 0x1 * 4, 0x7000 | PG_V | PG_U, 4);
+        mem.set(0x1000 + 0x1 * 4, 0x0080_0000 | PG_V | PG_RW | PG_PS, 4);
+        let user = paging(vm_paging_mode::PAGING_MODE_32, 0x1000, 3);
+        let kernel = paging(vm_paging_mode::PAGING_MODE_32, 0x1000, 0);
+        let options = WalkOptions::default();
+
+        let mapping = translate(&mem, &user, 0xc000_1234, FaultType::Execute, &options).unwrap();
+        assert_eq!(mapping, PageMapping { gpa: 0x7234, page_size: 0x1000 });
+
+        // No I/D bit without NX
+        assert_eq!(error_code(translate(&mem, &user, 0xc000_2000, FaultType::Execute, &options)), PGEX_U);
+        assert_eq!(error_code(translate(&mem, &user, 0xc000_1000, FaultType::Write, &options)),
+                   PGEX_P | PGEX_W | PGEX_U);
+
+        let mapping = translate(&mem, &kernel, 0x0051_2345, FaultType::Write, &options).unwrap();
+        assert_eq!(mapping, PageMapping { gpa: 0x0091_2345, page_size: 0x40_0000 });
+
+        // Without CR4.PSE, the PS bit is ignored and the entry points to a
+        // page table
+        mem.set(0x0080_0000, 0x9000 | PG_V, 4);
+        let no_pse = WalkOptions { pse: false, ..options };
+        let mapping = translate(&mem, &kernel, 0x0040_0123, FaultType::Read, &no_pse).unwrap();
+        assert_eq!(mapping, PageMapping { gpa: 0x9123, page_size: 0x1000 });
+
+        // With PSE-36, bits 20:13 of a 4MB page entry are address bits
+        // 39:32, up to MAXPHYADDR, and bit 21 is reserved
+        mem.set(0x1000 + 0x1 * 4, 0x0080_0000 | 0x3 << 13 | PG_V | PG_PS, 4);
+        let mapping = translate(&mem, &kernel, 0x0051_2345, FaultType::Read, &options).unwrap();
+        assert_eq!(mapping.gpa, 0x3_0091_2345);
+        let narrow = WalkOptions { maxphyaddr: 33, ..options };
+        assert_eq!(error_code(translate(&mem, &kernel, 0x0051_2345, FaultType::Read, &narrow)),
+                   PGEX_P | PGEX_RSV);
+        mem.set(0x1000 + 0x1 * 4, 0x0080_0000 | 1 << 21 | PG_V | PG_PS, 4);
+        assert_eq!(error_code(translate(&mem, &kernel, 0x0051_2345, FaultType::Read, &options)),
+                   PGEX_P | PGEX_RSV);
+    }
+
+    #[test]
+    fn test_pae() {
+        let mem = TestMemory::default();
+        // PDPT at 0x1020, PD at 0x2000, PT at 0x3000, mapping 0xc020_5000
+        // to 0x1_0000_8000
+        mem.set(0x1020 + 0x3 * 8, 0x2000 | PG_V, 8);
+        mem.set(0x2000 + 0x1 * 8, 0x3000 | RW_U, 8);
+        mem.set(0x3000 + 0x5 * 8, 0x1_0000_8000 | RW_U, 8);
+        let kernel = paging(vm_paging_mode::PAGING_MODE_PAE, 0x1020, 0);
+        let options = WalkOptions::default();
+
+        let mapping = translate(&mem, &kernel, 0xc020_5678, FaultType::Write, &options).unwrap();
+        assert_eq!(mapping, PageMapping { gpa: 0x1_0000_8678, page_size: 0x1000 });
+
+        // Not present PDPTE
+        assert_eq!(error_code(translate(&mem, &kernel, 0x4000_0000, FaultType::Write, &options)), PGEX_W);
+
+        // PDPTEs have no R/W, U/S or NX bits
+        mem.set(0x1020 + 0x3 * 8, 0x2000 | PG_V | PG_RW, 8);
+        assert_eq!(error_code(translate(&mem, &kernel, 0xc020_5678, FaultType::Read, &options)),
+                   PGEX_P | PGEX_RSV);
+
+        // Flat mode is an identity mapping
+        let flat = paging(vm_paging_mode::PAGING_MODE_FLAT, 0, 0);
+        let mapping = translate(&mem, &flat, 0x1234_5678, FaultType::Read, &options).unwrap();
+        assert_eq!(mapping.gpa, 0x1234_5678);
+    }
+}
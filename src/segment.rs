@@ -0,0 +1,386 @@
+//! Segment descriptors and linear address calculation.
+//!
+//! `SegmentDescriptor` interprets the access rights of a `seg_desc`, which
+//! use the layout of the VMX guest segment access rights fields, and
+//! computes the linear addresses of segment accesses with the checks the
+//! processor makes.
+
+use std::error;
+use std::fmt;
+
+use crate::decode::size_mask;
+use crate::include::segments::{IDT_GP, IDT_SS};
+use crate::vm::{seg_desc, vm_cpu_mode, vm_reg_name, FaultType};
+
+// Bits of the access rights.
+const ACCESS_TYPE: u32 = 0xf;
+const ACCESS_S: u32 = 1 << 4;
+const ACCESS_DPL_SHIFT: u32 = 5;
+const ACCESS_DPL: u32 = 0x3 << ACCESS_DPL_SHIFT;
+const ACCESS_P: u32 = 1 << 7;
+const ACCESS_AVL: u32 = 1 << 12;
+const ACCESS_L: u32 = 1 << 13;
+const ACCESS_DB: u32 = 1 << 14;
+const ACCESS_G: u32 = 1 << 15;
+const ACCESS_UNUSABLE: u32 = 1 << 16;
+
+// Bits of the type of code and data segments.
+const TYPE_ACCESSED: u8 = 0x1;
+const TYPE_WRITABLE: u8 = 0x2;      // data: writable, code: readable
+const TYPE_EXPAND_DOWN: u8 = 0x4;   // data only
+const TYPE_CODE: u8 = 0x8;
+
+// Types of system segments.
+const TYPE_LDT: u8 = 0x2;
+const TYPE_TSS_BUSY: u8 = 0xb;
+
+/// A segment descriptor, as held in the hidden part of a segment register.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SegmentDescriptor {
+    pub base: u64,
+    /// Limit in bytes, already scaled by the granularity
+    pub limit: u32,
+    /// Type of segment, whose meaning depends on 'code_or_data'
+    pub seg_type: u8,
+    /// S flag: a code or data segment, rather than a system segment
+    pub code_or_data: bool,
+    /// Descriptor privilege level
+    pub dpl: u8,
+    pub present: bool,
+    /// Available for use by system software
+    pub avl: bool,
+    /// L flag: a 64-bit code segment
+    pub long: bool,
+    /// D/B flag: 32-bit default operand size or stack pointer, and upper
+    /// bound of expand-down segments
+    pub default_big: bool,
+    /// G flag: the limit is in 4KB units in the descriptor table
+    pub granularity: bool,
+    /// The segment register is unusable, such as after loading a null
+    /// selector
+    pub unusable: bool,
+}
+
+impl SegmentDescriptor {
+    /// Decodes a segment descriptor from its base, limit and access rights.
+    pub fn from_access(base: u64, limit: u32, access: u32) -> SegmentDescriptor {
+        SegmentDescriptor {
+            base: base,
+            limit: limit,
+            seg_type: (access & ACCESS_TYPE) as u8,
+            code_or_data: (access & ACCESS_S) != 0,
+            dpl: ((access & ACCESS_DPL) >> ACCESS_DPL_SHIFT) as u8,
+            present: (access & ACCESS_P) != 0,
+            avl: (access & ACCESS_AVL) != 0,
+            long: (access & ACCESS_L) != 0,
+            default_big: (access & ACCESS_DB) != 0,
+            granularity: (access & ACCESS_G) != 0,
+            unusable: (access & ACCESS_UNUSABLE) != 0,
+        }
+    }
+
+    /// Decodes a `seg_desc`, as reported by the kernel.
+    pub fn from_desc(desc: &seg_desc) -> SegmentDescriptor {
+        SegmentDescriptor::from_access(desc.base, desc.limit, desc.access)
+    }
+
+    /// Encodes the access rights.
+    pub fn access(&self) -> u32 {
+        let mut access = (self.seg_type as u32 & ACCESS_TYPE) |
+            ((self.dpl as u32) << ACCESS_DPL_SHIFT & ACCESS_DPL);
+        let flags = [
+            (self.code_or_data, ACCESS_S),
+            (self.present, ACCESS_P),
+            (self.avl, ACCESS_AVL),
+            (self.long, ACCESS_L),
+            (self.default_big, ACCESS_DB),
+            (self.granularity, ACCESS_G),
+            (self.unusable, ACCESS_UNUSABLE),
+        ];
+        for (set, bit) in flags.iter() {
+            if *set {
+                access |= bit;
+            }
+        }
+        return access;
+    }
+
+    /// Encodes the descriptor as a `seg_desc`.
+    pub fn desc(&self) -> seg_desc {
+        seg_desc {
+            base: self.base,
+            limit: self.limit,
+            access: self.access(),
+        }
+    }
+
+    // A present descriptor of type 'seg_type', with the other flags clear.
+    fn new(base: u64, limit: u32, seg_type: u8, code_or_data: bool) -> SegmentDescriptor {
+        SegmentDescriptor {
+            base: base,
+            limit: limit,
+            seg_type: seg_type,
+            code_or_data: code_or_data,
+            dpl: 0,
+            present: true,
+            avl: false,
+            long: false,
+            default_big: false,
+            granularity: false,
+            unusable: false,
+        }
+    }
+
+    /// A 64KB read/write data segment at 'base', as loaded into the segment
+    /// registers in real mode and at reset.
+    pub fn real_mode(base: u64) -> SegmentDescriptor {
+        SegmentDescriptor::new(base, 0xffff, TYPE_WRITABLE | TYPE_ACCESSED, true)
+    }
+
+    /// A busy task state segment, as loaded into TR.
+    pub fn tss(base: u64, limit: u32) -> SegmentDescriptor {
+        SegmentDescriptor::new(base, limit, TYPE_TSS_BUSY, false)
+    }
+
+    /// A local descriptor table, as loaded into LDTR.
+    pub fn ldt(base: u64, limit: u32) -> SegmentDescriptor {
+        SegmentDescriptor::new(base, limit, TYPE_LDT, false)
+    }
+
+    /// Returns true for a code segment.
+    pub fn is_code(&self) -> bool {
+        self.code_or_data && (self.seg_type & TYPE_CODE) != 0
+    }
+
+    /// Returns true for a data segment.
+    pub fn is_data(&self) -> bool {
+        self.code_or_data && (self.seg_type & TYPE_CODE) == 0
+    }
+
+    /// Returns true for an expand-down data segment, whose valid offsets
+    /// are above the limit.
+    pub fn is_expand_down(&self) -> bool {
+        self.is_data() && (self.seg_type & TYPE_EXPAND_DOWN) != 0
+    }
+
+    /// Computes the linear address of an access of 'size' bytes at 'offset'
+    /// in this segment, which is loaded in segment register 'reg'. The
+    /// offset is truncated to the address size 'addrsize' (2, 4, or 8
+    /// bytes).
+    ///
+    /// Outside of 64-bit mode, the access must lie within the limit, and
+    /// outside of real mode, the segment type must also permit it. In
+    /// 64-bit mode, only FS and GS have a base, and the address must be
+    /// canonical with 48-bit linear addresses. Failed checks report the
+    /// fault the processor raises: #SS for SS, and #GP otherwise.
+    pub fn linear_address(&self, reg: vm_reg_name, cpu_mode: vm_cpu_mode, addrsize: u8,
+                          offset: u64, size: u8, access: FaultType) -> Result<u64, SegmentFault> {
+        let fault = if reg == vm_reg_name::VM_REG_GUEST_SS {
+            SegmentFault::StackFault
+        } else {
+            SegmentFault::GeneralProtection
+        };
+        let mask = size_mask(addrsize);
+        let offset = offset & mask;
+
+        if cpu_mode == vm_cpu_mode::CPU_MODE_64BIT {
+            let base = match reg {
+                vm_reg_name::VM_REG_GUEST_FS | vm_reg_name::VM_REG_GUEST_GS => self.base,
+                _ => 0,
+            };
+            let gla = base.wrapping_add(offset);
+            let last = gla.wrapping_add(size as u64 - 1);
+            if !is_canonical(gla) || !is_canonical(last) {
+                return Err(fault);
+            }
+            return Ok(gla);
+        }
+
+        // Real mode doesn't check the access rights, which are left over
+        // from reset or the last protected mode load of the register.
+        if cpu_mode != vm_cpu_mode::CPU_MODE_REAL {
+            if self.unusable || !self.present {
+                return Err(fault);
+            }
+            let allowed = match access {
+                FaultType::Read => !self.is_code() || (self.seg_type & TYPE_WRITABLE) != 0,
+                FaultType::Write => self.is_data() && (self.seg_type & TYPE_WRITABLE) != 0,
+                FaultType::Execute => self.is_code(),
+            };
+            if !allowed {
+                return Err(fault);
+            }
+        }
+
+        // Expand-down segments span from above the limit to the upper bound
+        // set by the B flag.
+        let (low, high) = if self.is_expand_down() {
+            let high = if self.default_big { 0xffff_ffff } else { 0xffff };
+            (self.limit as u64 + 1, high)
+        } else {
+            (0, self.limit as u64)
+        };
+        // An access that extends past the limit faults, rather than
+        // wrapping around within the segment.
+        let last = offset + size as u64 - 1;
+        if offset < low || last > high {
+            return Err(fault);
+        }
+
+        // Linear addresses are 32 bits wide outside of 64-bit mode
+        return Ok(self.base.wrapping_add(offset) & 0xffff_ffff);
+    }
+}
+
+// Checks that bits 63:47 of a linear address are all the same.
+fn is_canonical(gla: u64) -> bool {
+    let upper = (gla as i64) >> 47;
+    upper == 0 || upper == -1
+}
+
+/// Faults raised by segment accesses.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SegmentFault {
+    /// General protection fault, #GP(0)
+    GeneralProtection,
+    /// Stack fault, #SS(0), for accesses through SS
+    StackFault,
+}
+
+impl SegmentFault {
+    /// Returns the exception vector, for `VirtualMachine::inject_exception`.
+    /// The error code is 0.
+    pub fn vector(&self) -> i32 {
+        match self {
+            SegmentFault::GeneralProtection => IDT_GP,
+            SegmentFault::StackFault => IDT_SS,
+        }
+    }
+}
+
+impl fmt::Display for SegmentFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SegmentFault::GeneralProtection => write!(f, "general protection fault"),
+            SegmentFault::StackFault => write!(f, "stack fault"),
+        }
+    }
+}
+
+impl error::Error for SegmentFault {}
+
+#[cfg(test)]
+mod tests {
+    use crate::segment::*;
+
+    const PROTECTED: vm_cpu_mode = vm_cpu_mode::CPU_MODE_PROTECTED;
+    const DS: vm_reg_name = vm_reg_name::VM_REG_GUEST_DS;
+    const SS: vm_reg_name = vm_reg_name::VM_REG_GUEST_SS;
+
+    #[test]
+    fn test_access_rights() {
+        assert_eq!(SegmentDescriptor::real_mode(0).access(), 0x0093);
+        assert_eq!(SegmentDescriptor::tss(0, 0).access(), 0x008b);
+        assert_eq!(SegmentDescriptor::ldt(0, 0xffff).access(), 0x0082);
+
+        // A flat 64-bit ring 3 code segment
+        let desc = SegmentDescriptor::from_access(0, 0xffffffff, 0xa0fb);
+        assert!(desc.is_code());
+        assert_eq!(desc.seg_type, 0xb);
+        assert_eq!(desc.dpl, 3);
+        assert!(desc.present && desc.long && desc.granularity);
+        assert!(!desc.default_big && !desc.avl && !desc.unusable);
+        assert_eq!(desc.access(), 0xa0fb);
+
+        let desc = SegmentDescriptor::from_desc(&seg_desc { base: 0x1000, limit: 0, access: 0x1d0f3 });
+        assert!(desc.unusable && desc.avl && desc.default_big);
+        assert_eq!(desc.desc(), seg_desc { base: 0x1000, limit: 0, access: 0x1d0f3 });
+    }
+
+    #[test]
+    fn test_linear_address() {
+        let desc = SegmentDescriptor::real_mode(0xf0000);
+        assert_eq!(desc.linear_address(DS, vm_cpu_mode::CPU_MODE_REAL, 2, 0xfffe, 2, FaultType::Write),
+                   Ok(0xffffe));
+        assert_eq!(desc.linear_address(DS, PROTECTED, 4, 0xffff, 2, FaultType::Read),
+                   Err(SegmentFault::GeneralProtection));
+        // Accesses that extend past the limit don't wrap at the address size
+        assert_eq!(desc.linear_address(DS, PROTECTED, 2, 0xffff, 2, FaultType::Read),
+                   Err(SegmentFault::GeneralProtection));
+        assert_eq!(desc.linear_address(DS, vm_cpu_mode::CPU_MODE_REAL, 2, 0xffff, 2, FaultType::Read),
+                   Err(SegmentFault::GeneralProtection));
+        assert_eq!(desc.linear_address(SS, PROTECTED, 4, 0x10000, 1, FaultType::Read),
+                   Err(SegmentFault::StackFault));
+
+        // The base wraps at 4GB
+        let desc = SegmentDescriptor { base: 0xffff_f000, limit: 0xffff_ffff, ..desc };
+        assert_eq!(desc.linear_address(DS, PROTECTED, 4, 0x2000, 4, FaultType::Read), Ok(0x1000));
+
+        // Read-only data and execute-only code
+        let read_only = SegmentDescriptor { seg_type: TYPE_ACCESSED, ..desc };
+        assert!(read_only.linear_address(DS, PROTECTED, 4, 0, 1, FaultType::Read).is_ok());
+        assert_eq!(read_only.linear_address(DS, PROTECTED, 4, 0, 1, FaultType::Write),
+                   Err(SegmentFault::GeneralProtection));
+        let code = SegmentDescriptor { seg_type: TYPE_CODE, ..desc };
+        assert!(code.linear_address(DS, PROTECTED, 4, 0, 1, FaultType::Execute).is_ok());
+        assert!(code.linear_address(DS, PROTECTED, 4, 0, 1, FaultType::Read).is_err());
+
+        let unusable = SegmentDescriptor { unusable: true, ..desc };
+        assert!(unusable.linear_address(DS, PROTECTED, 4, 0, 1, FaultType::Read).is_err());
+    }
+
+    #[test]
+    fn test_real_mode() {
+        let real = vm_cpu_mode::CPU_MODE_REAL;
+        let cs = vm_reg_name::VM_REG_GUEST_CS;
+
+        // Fetches through the data segment CS is loaded with at reset
+        let desc = SegmentDescriptor::real_mode(0xffff_0000);
+        assert_eq!(desc.linear_address(cs, real, 2, 0xfff0, 1, FaultType::Execute), Ok(0xffff_fff0));
+        assert_eq!(desc.linear_address(cs, PROTECTED, 2, 0xfff0, 1, FaultType::Execute),
+                   Err(SegmentFault::GeneralProtection));
+
+        // Only the limit is checked
+        let desc = SegmentDescriptor { seg_type: TYPE_CODE, present: false, ..desc };
+        assert_eq!(desc.linear_address(DS, real, 2, 0x10, 2, FaultType::Write), Ok(0xffff_0010));
+        assert_eq!(desc.linear_address(SS, real, 4, 0x10000, 2, FaultType::Write),
+                   Err(SegmentFault::StackFault));
+    }
+
+    #[test]
+    fn test_expand_down() {
+        // A 16-bit expand-down stack with offsets 0x1000 to 0xffff
+        let desc = SegmentDescriptor::from_access(0x10000, 0xfff, 0x0097);
+        assert!(desc.is_expand_down());
+        assert_eq!(desc.linear_address(SS, PROTECTED, 2, 0x1000, 2, FaultType::Write), Ok(0x11000));
+        assert_eq!(desc.linear_address(SS, PROTECTED, 2, 0xfff, 2, FaultType::Write),
+                   Err(SegmentFault::StackFault));
+        assert_eq!(desc.linear_address(SS, PROTECTED, 4, 0xfffe, 4, FaultType::Write),
+                   Err(SegmentFault::StackFault));
+
+        // The B flag raises the upper bound to 4GB
+        let big = SegmentDescriptor { default_big: true, ..desc };
+        assert_eq!(big.linear_address(SS, PROTECTED, 4, 0xfffe, 4, FaultType::Write), Ok(0x1fffe));
+    }
+
+    #[test]
+    fn test_64bit() {
+        let mode = vm_cpu_mode::CPU_MODE_64BIT;
+        let desc = SegmentDescriptor { base: 0x7fff_0000_0000, ..SegmentDescriptor::real_mode(0) };
+
+        // Only FS and GS have a base, and limits aren't checked
+        assert_eq!(desc.linear_address(DS, mode, 8, 0x12345678, 8, FaultType::Read), Ok(0x12345678));
+        assert_eq!(desc.linear_address(vm_reg_name::VM_REG_GUEST_FS, mode, 8, 0x10, 8, FaultType::Read),
+                   Ok(0x7fff_0000_0010));
+
+        assert_eq!(desc.linear_address(vm_reg_name::VM_REG_GUEST_GS, mode, 8, 0xffff_fffc, 8, FaultType::Read),
+                   Err(SegmentFault::GeneralProtection));
+        assert_eq!(desc.linear_address(SS, mode, 8, 0x8000_0000_0000, 8, FaultType::Read),
+                   Err(SegmentFault::StackFault));
+        assert_eq!(desc.linear_address(SS, mode, 8, 0xffff_8000_0000_0000, 8, FaultType::Read),
+                   Ok(0xffff_8000_0000_0000));
+
+        // The offset is truncated to the address size
+        assert_eq!(desc.linear_address(DS, mode, 4, 0x1_0000_0010, 1, FaultType::Read), Ok(0x10));
+    }
+}
@@ -0,0 +1,222 @@
+//! AMD SVM exit codes and exit information.
+//!
+//! When Bhyve has no better exit code for a VM exit on an AMD host, it
+//! reports the raw SVM exit code and the EXITINFO1 and EXITINFO2 fields in
+//! `VmExit::Svm`. The types here decode those values according to the AMD64
+//! Architecture Programmer's Manual, Vol. 2, Appendix C (exit codes) and
+//! Section 15 (exit information).
+
+use crate::vm::FaultType;
+
+code_enum! {
+    /// SVM exit codes, from the EXITCODE field of the VMCB.
+    pub enum SvmExitCode: u64 {
+        Intr = 0x60,
+        Nmi = 0x61,
+        Smi = 0x62,
+        Init = 0x63,
+        Vintr = 0x64,
+        Cr0SelWrite = 0x65,
+        IdtrRead = 0x66,
+        GdtrRead = 0x67,
+        LdtrRead = 0x68,
+        TrRead = 0x69,
+        IdtrWrite = 0x6a,
+        GdtrWrite = 0x6b,
+        LdtrWrite = 0x6c,
+        TrWrite = 0x6d,
+        Rdtsc = 0x6e,
+        Rdpmc = 0x6f,
+        Pushf = 0x70,
+        Popf = 0x71,
+        Cpuid = 0x72,
+        Rsm = 0x73,
+        Iret = 0x74,
+        Swint = 0x75,
+        Invd = 0x76,
+        Pause = 0x77,
+        Hlt = 0x78,
+        Invlpg = 0x79,
+        Invlpga = 0x7a,
+        Ioio = 0x7b,
+        Msr = 0x7c,
+        TaskSwitch = 0x7d,
+        FerrFreeze = 0x7e,
+        Shutdown = 0x7f,
+        Vmrun = 0x80,
+        Vmmcall = 0x81,
+        Vmload = 0x82,
+        Vmsave = 0x83,
+        Stgi = 0x84,
+        Clgi = 0x85,
+        Skinit = 0x86,
+        Rdtscp = 0x87,
+        Icebp = 0x88,
+        Wbinvd = 0x89,
+        Monitor = 0x8a,
+        Mwait = 0x8b,
+        MwaitConditional = 0x8c,
+        Xsetbv = 0x8d,
+        Rdpru = 0x8e,
+        EferWriteTrap = 0x8f,
+        Npf = 0x400,
+        AvicIncompleteIpi = 0x401,
+        AvicNoaccel = 0x402,
+        Vmgexit = 0x403,
+        /// The VMCB failed the consistency checks of VMRUN
+        Invalid = 0xffff_ffff_ffff_ffff,
+    }
+    ranges {
+        /// Read of a control register, by number
+        CrRead = 0x00..=0x0f,
+        /// Write of a control register, by number
+        CrWrite = 0x10..=0x1f,
+        /// Read of a debug register, by number
+        DrRead = 0x20..=0x2f,
+        /// Write of a debug register, by number
+        DrWrite = 0x30..=0x3f,
+        /// Exception, by vector
+        Exception = 0x40..=0x5f,
+        /// Trap after a write of a control register, by number
+        CrWriteTrap = 0x90..=0x9f,
+    }
+}
+
+/// EXITINFO1 for IOIO intercepts (APM Vol. 2, Figure 15-2).
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct IoioInfo {
+    /// Size of the access in bytes: 1, 2, or 4
+    pub bytes: u8,
+    /// Address size of a string instruction in bytes: 2, 4, or 8
+    pub addrsize: u8,
+    /// The instruction is IN or INS, rather than OUT or OUTS
+    pub is_in: bool,
+    /// The instruction is INS or OUTS
+    pub is_string: bool,
+    /// The instruction has a REP prefix
+    pub is_repeat: bool,
+    /// Effective segment of a string instruction, encoded as in ModRM
+    pub segment: u8,
+    pub port: u16,
+}
+
+impl IoioInfo {
+    pub fn from_exitinfo(exitinfo1: u64) -> IoioInfo {
+        // The size fields are one-hot encoded
+        let bytes = match (exitinfo1 >> 4) & 0x7 {
+            0x1 => 1,
+            0x2 => 2,
+            _ => 4,
+        };
+        let addrsize = match (exitinfo1 >> 7) & 0x7 {
+            0x1 => 2,
+            0x2 => 4,
+            _ => 8,
+        };
+        IoioInfo {
+            bytes: bytes,
+            addrsize: addrsize,
+            is_in: (exitinfo1 & (1 << 0)) != 0,
+            is_string: (exitinfo1 & (1 << 2)) != 0,
+            is_repeat: (exitinfo1 & (1 << 3)) != 0,
+            segment: ((exitinfo1 >> 10) & 0x7) as u8,
+            port: ((exitinfo1 >> 16) & 0xffff) as u16,
+        }
+    }
+}
+
+/// EXITINFO1 and EXITINFO2 for nested page faults (APM Vol. 2, Section
+/// 15.25.6).
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct NpfInfo {
+    /// Faulting guest physical address
+    pub gpa: u64,
+    /// The nested page was present
+    pub present: bool,
+    /// The access was a write
+    pub write: bool,
+    /// The access was a user-mode access
+    pub user: bool,
+    /// A reserved bit was set in the nested page tables
+    pub reserved: bool,
+    /// The access was an instruction fetch
+    pub fetch: bool,
+    /// The fault occurred on the final guest physical address
+    pub final_translation: bool,
+    /// The fault occurred while walking the guest page tables
+    pub page_table_walk: bool,
+}
+
+impl NpfInfo {
+    pub fn from_exitinfo(exitinfo1: u64, exitinfo2: u64) -> NpfInfo {
+        NpfInfo {
+            gpa: exitinfo2,
+            present: (exitinfo1 & (1 << 0)) != 0,
+            write: (exitinfo1 & (1 << 1)) != 0,
+            user: (exitinfo1 & (1 << 2)) != 0,
+            reserved: (exitinfo1 & (1 << 3)) != 0,
+            fetch: (exitinfo1 & (1 << 4)) != 0,
+            final_translation: (exitinfo1 & (1 << 32)) != 0,
+            page_table_walk: (exitinfo1 & (1 << 33)) != 0,
+        }
+    }
+
+    /// Returns the type of the faulting access.
+    pub fn fault_type(&self) -> FaultType {
+        if self.fetch {
+            return FaultType::Execute;
+        } else if self.write {
+            return FaultType::Write;
+        } else {
+            return FaultType::Read;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::svm::*;
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(SvmExitCode::from_code(0x7f), SvmExitCode::Shutdown);
+        assert_eq!(SvmExitCode::from_code(0x400), SvmExitCode::Npf);
+        assert_eq!(SvmExitCode::from_code(!0), SvmExitCode::Invalid);
+        assert_eq!(SvmExitCode::from_code(0x03), SvmExitCode::CrRead(3));
+        assert_eq!(SvmExitCode::from_code(0x4e), SvmExitCode::Exception(14));
+        assert_eq!(SvmExitCode::from_code(0x500), SvmExitCode::Unknown(0x500));
+        assert_eq!(SvmExitCode::CrWrite(4).code(), 0x14);
+        assert_eq!(SvmExitCode::Vmmcall.code(), 0x81);
+    }
+
+    #[test]
+    fn test_ioio_info() {
+        // out %al, $0x80
+        let io = IoioInfo::from_exitinfo(0x00800010);
+        assert_eq!(io.bytes, 1);
+        assert_eq!(io.is_in, false);
+        assert_eq!(io.is_string, false);
+        assert_eq!(io.port, 0x80);
+
+        // rep insw (%dx), %es:(%rdi) with DX = 0x1f0 in 64-bit mode
+        let io = IoioInfo::from_exitinfo(0x01f0022d);
+        assert_eq!(io.bytes, 2);
+        assert_eq!(io.addrsize, 8);
+        assert_eq!(io.is_in, true);
+        assert_eq!(io.is_string, true);
+        assert_eq!(io.is_repeat, true);
+        assert_eq!(io.port, 0x1f0);
+    }
+
+    #[test]
+    fn test_npf_info() {
+        let npf = NpfInfo::from_exitinfo(0x1_0000_0006, 0xfee0_0000);
+        assert_eq!(npf.gpa, 0xfee0_0000);
+        assert_eq!(npf.present, false);
+        assert_eq!(npf.write, true);
+        assert_eq!(npf.user, true);
+        assert_eq!(npf.final_translation, true);
+        assert_eq!(npf.page_table_walk, false);
+        assert_eq!(npf.fault_type(), FaultType::Write);
+    }
+}
@@ -3,10 +3,12 @@
 use libc::{ioctl, open, O_EXCL, O_RDWR, EINVAL};
 use std::ffi::CString;
 use std::fs::File;
-use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::Mutex;
 
-use crate::include::vmm_dev::{VMM_CREATE_VM, VMM_DESTROY_VM};
-use crate::Error;
+use crate::include::vmm_dev::{VMM_CREATE_VM, VMM_DESTROY_VM, VM_ABIVERS, VMM_ABI_VERSIONS};
+use crate::vm::VirtualMachine;
+use crate::{Error, OpenError};
 
 /// The VMMSystem module handles VMM system operations. It creates and
 /// owns the initial filehandle on `/dev/vmmctl`.
@@ -18,22 +20,55 @@ use crate::Error;
 
 pub struct VMMSystem {
     vmmctl: File,
+    abi_version: Mutex<Option<i32>>,
+}
+
+/// Checks that the ABI version 'version' reported by the kernel is one of
+/// the versions whose struct layouts are compiled into this library.
+///
+/// Returns Ok containing the version if it is supported, and an OpenError
+/// listing the supported versions otherwise.
+pub(crate) fn check_abi_version(version: i32) -> Result<i32, OpenError> {
+    if !VMM_ABI_VERSIONS.contains(&version) {
+        return Err(OpenError::IncompatibleAbi {
+            found: version,
+            supported: VMM_ABI_VERSIONS,
+        });
+    }
+    Ok(version)
+}
+
+/// Queries the ABI version of the kernel through the open virtual machine
+/// device filehandle 'fd', and checks that it is supported.
+pub(crate) fn query_abi_version(fd: RawFd) -> Result<i32, OpenError> {
+    // Integer is allocated (and owned) by Rust, but modified by C
+    let mut version: i32 = 0;
+    let result = unsafe { ioctl(fd, VM_ABIVERS, &mut version) };
+    if result != 0 {
+        return Err(OpenError::Sys(Error::last()));
+    }
+    check_abi_version(version)
 }
 
 impl VMMSystem {
     /// Opens a filehandle to `/dev/vmmctl`, and returns a `Result`. If the open
-    /// operation fails, the `Result` unwraps as an `Error`. If it succeeds, the
-    /// `Result` unwraps as an instance of `VMMSystem` for performing VMM system
-    /// operations.
+    /// operation fails, the `Result` unwraps as an `OpenError`. If it succeeds,
+    /// the `Result` unwraps as an instance of `VMMSystem` for performing VMM
+    /// system operations.
+    ///
+    /// `/dev/vmmctl` doesn't answer `VM_ABIVERS`, which is only handled by
+    /// virtual machine devices, so the kernel's ABI version can't be checked
+    /// here. It is checked on the first virtual machine created through the
+    /// handle instead, see `create_vm`.
 
-    pub fn new() -> Result<VMMSystem, Error> {
+    pub fn new() -> Result<VMMSystem, OpenError> {
         let c_path = match CString::new("/dev/vmmctl") {
             Ok(s) => s,
-            Err(_) => return Err(Error::new(EINVAL))
+            Err(_) => return Err(OpenError::Sys(Error::new(EINVAL)))
         };
         let raw_fd = unsafe { open(c_path.as_ptr(), O_RDWR | O_EXCL) };
         if raw_fd < 0 {
-            return Err(Error::last());
+            return Err(OpenError::Sys(Error::last()));
         }
         let safe_handle = unsafe { File::from_raw_fd(raw_fd) };
 
@@ -41,25 +76,48 @@ impl VMMSystem {
         // and ownership of File struct is consumed by KVMSystem struct.
         Ok(VMMSystem {
             vmmctl: safe_handle,
+            abi_version: Mutex::new(None),
         })
     }
 
+    /// Returns the ABI version reported by the kernel for the first virtual
+    /// machine created through this handle, or `None` if no virtual machine
+    /// has been created yet.
+    pub fn abi_version(&self) -> Option<i32> {
+        *self.abi_version.lock().unwrap()
+    }
+
     /// Creates a device for virtual machine operation at `/dev/vmm/[name]`,
     /// and returns a `Result`. If the creation operation fails, the `Result`
-    /// unwraps as an `Error`. If it succeeds, the `Result` unwraps as `i32`
+    /// unwraps as an `OpenError`. If it succeeds, the `Result` unwraps as `i32`
     /// integer containing the integer return value of the ioctl operation.
+    ///
+    /// The first virtual machine created through the handle is opened to
+    /// check the kernel's ABI version. If the version isn't supported by this
+    /// library, the device is destroyed again and the `Result` unwraps as
+    /// `OpenError::IncompatibleAbi`.
 
-    pub fn create_vm(&self, name: &str) -> Result<i32, Error> {
+    pub fn create_vm(&self, name: &str) -> Result<i32, OpenError> {
         let c_name = match CString::new(name) {
             Ok(s) => s,
-            Err(_) => return Err(Error::new(EINVAL))
+            Err(_) => return Err(OpenError::Sys(Error::new(EINVAL)))
         };
         let result = unsafe { ioctl(self.vmmctl.as_raw_fd(), VMM_CREATE_VM, c_name.as_ptr()) };
         if result == -1 {
-            return Err(Error::last());
-        } else {
-            return Ok(result);
+            return Err(OpenError::Sys(Error::last()));
         }
+
+        let mut abi_version = self.abi_version.lock().unwrap();
+        if abi_version.is_none() {
+            match VirtualMachine::new(name) {
+                Ok(vm) => *abi_version = Some(vm.abi_version()),
+                Err(e) => {
+                    let _ = self.destroy_vm(name);
+                    return Err(e);
+                }
+            }
+        }
+        return Ok(result);
     }
 
     /// Destroys a device for virtual machine operations at `/dev/vmm/[name]`,
@@ -80,3 +138,25 @@ impl VMMSystem {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::system::*;
+
+    #[test]
+    fn test_check_abi_version() {
+        for version in VMM_ABI_VERSIONS {
+            assert_eq!(check_abi_version(*version).unwrap(), *version);
+        }
+
+        match check_abi_version(0) {
+            Err(OpenError::IncompatibleAbi { found, supported }) => {
+                assert_eq!(found, 0);
+                assert_eq!(supported, VMM_ABI_VERSIONS);
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        let message = check_abi_version(0).unwrap_err().to_string();
+        assert_eq!(message, "kernel bhyve ABI version 0 is incompatible with this library (supported versions: 1)");
+    }
+}
@@ -1,15 +1,25 @@
 //! Bhyve virtual machine operations.
 
-use libc::{ioctl, open, O_RDWR, c_void, sysconf, _SC_PAGESIZE, EINVAL, EFAULT};
+use libc::{ioctl, open, O_RDWR, c_int, c_ulong, c_void, sysconf, _SC_PAGESIZE, EINVAL, EFAULT, ENOENT};
 use std::ffi::{CString, CStr};
 use std::fs::File;
 use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::ptr::null_mut;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::fmt;
 
-pub use crate::include::vmm::{vm_cap_type, vm_reg_name};
-use crate::include::vmm::{vm_suspend_how, vm_exitcode, x2apic_state, seg_desc};
+pub use crate::include::vmm::{vm_cap_type, vm_reg_name, vm_guest_paging, vm_cpu_mode, vm_paging_mode, seg_desc};
+use crate::include::vmm::{vm_exit, vm_suspend_how, vm_exitcode, task_switch_reason, vm_intr_trigger, x2apic_state, VM_MAXCPU};
 use crate::include::vmm_dev::*;
 use crate::include::specialreg::{CR0_NE};
-use crate::Error;
+use crate::include::segments::{IDT_GP};
+use crate::include::psl::{PSL_I};
+use crate::vmx::{VmxExitReason, IoInstruction, EptViolation};
+use crate::svm::{SvmExitCode, IoioInfo, NpfInfo};
+use crate::segment::SegmentDescriptor;
+use crate::system::query_abi_version;
+use crate::{Error, OpenError};
 
 const MB: u64 = 1024 * 1024;
 const GB: u64 = 1024 * MB;
@@ -25,39 +35,52 @@ const MAX_BOOTROM_SIZE: usize = 16 * MB as usize;
 /// It owns the filehandle for these operations.
 pub struct VirtualMachine {
     vm: File,
+    abi_version: i32,
     pub name: String,
     pub lowmem_limit: usize,
     pub memflags: i32,
+    stat_names: Mutex<Vec<String>>,
 }
 
 impl VirtualMachine {
     /// Opens a filehandle to an existing virtual machine device by name, and
-    /// returns a `Result`. If the open  operation fails, the `Result` unwraps
-    /// as an `Error`. If it succeeds, the `Result` unwraps as an instance of
+    /// returns a `Result`. If the open  operation fails, or the kernel's ABI
+    /// version is not supported by this library, the `Result` unwraps as an
+    /// `OpenError`. If it succeeds, the `Result` unwraps as an instance of
     /// `VirtualMachine`.
 
-    pub fn new(name: &str) -> Result<VirtualMachine, Error> {
+    pub fn new(name: &str) -> Result<VirtualMachine, OpenError> {
         let path = format!("/dev/vmm/{}", name);
         let c_path = match CString::new(path) {
             Ok(s) => s,
-            Err(_) => return Err(Error::new(EINVAL))
+            Err(_) => return Err(OpenError::Sys(Error::new(EINVAL)))
         };
         let raw_fd = unsafe { open(c_path.as_ptr(), O_RDWR) };
         if raw_fd < 0 {
-            return Err(Error::last());
+            return Err(OpenError::Sys(Error::last()));
         }
         let safe_handle = unsafe { File::from_raw_fd(raw_fd) };
+        let abi_version = query_abi_version(safe_handle.as_raw_fd())?;
 
         // Return value is safe because raw file descriptor result is checked
         // and ownership of File struct is consumed by VirtualMachine struct.
         Ok(VirtualMachine {
             vm: safe_handle,
+            abi_version: abi_version,
             name: name.to_string(),
             lowmem_limit: 3 * GB as usize,
             memflags: 0,
+            stat_names: Mutex::new(Vec::new()),
         })
     }
 
+    /// Returns the ABI version reported by the kernel when the filehandle
+    /// was opened, which is always one of the versions supported by this
+    /// library.
+    pub fn abi_version(&self) -> i32 {
+        self.abi_version
+    }
+
     /// Map the memory segment identified by 'segid' into the guest address space
     /// at [gpa,gpa+len) with protection 'prot'.
     pub fn mmap_memseg(&self, gpa: u64, segid: i32, off: i64, len: usize, prot: i32) -> Result<bool, Error> {
@@ -123,6 +146,76 @@ impl VirtualMachine {
         }
     }
 
+    /// Translate the guest linear address 'gla' to a guest physical address,
+    /// walking the guest page tables described by 'paging' for an access of
+    /// type 'prot' (PROT_READ or PROT_WRITE).
+    ///
+    /// If the translation fails, the kernel injects the resulting exception
+    /// into the VCPU and the `Result` unwraps as `Translation::Fault`.
+    pub fn gla2gpa(&self, vcpu_id: i32, paging: &vm_guest_paging, gla: u64, prot: i32) -> Result<Translation, Error> {
+        self.gla2gpa_ioctl(VM_GLA2GPA, vcpu_id, paging, gla, prot)
+    }
+
+    /// Translate the guest linear address 'gla' to a guest physical address,
+    /// like `gla2gpa`, but never inject an exception into the VCPU. This is
+    /// suitable for debuggers inspecting guest memory.
+    pub fn gla2gpa_nofault(&self, vcpu_id: i32, paging: &vm_guest_paging, gla: u64, prot: i32) -> Result<Translation, Error> {
+        self.gla2gpa_ioctl(VM_GLA2GPA_NOFAULT, vcpu_id, paging, gla, prot)
+    }
+
+    fn gla2gpa_ioctl(&self, op: c_int, vcpu_id: i32, paging: &vm_guest_paging, gla: u64, prot: i32) -> Result<Translation, Error> {
+        // Struct is allocated (and owned) by Rust, but modified by C
+        let mut gla_data = vm_gla2gpa {
+            vcpuid: vcpu_id,
+            prot: prot,
+            gla: gla,
+            paging: *paging,
+            fault: 0,
+            gpa: 0,
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), op, &mut gla_data) };
+        if result == 0 {
+            if gla_data.fault != 0 {
+                return Ok(Translation::Fault);
+            } else {
+                return Ok(Translation::Gpa(gla_data.gpa));
+            }
+        } else {
+            return Err(Error::last());
+        }
+    }
+
+    /// Iterate over the mappings in the guest physical address space, in order
+    /// of ascending guest physical address. This includes mappings created by
+    /// other processes, such as bhyveload(8).
+    ///
+    /// The iteration ends after the last mapping. If querying a mapping fails
+    /// for another reason, the error is returned as the last item, so that a
+    /// truncated map isn't mistaken for a complete one.
+    pub fn memory_map(&self) -> MemoryMap<'_> {
+        MemoryMap {
+            vm: self,
+            next_gpa: Some(0),
+        }
+    }
+
+    /// Find the mapping in the guest physical address space that contains
+    /// 'gpa', such as the faulting address of a `VmExit::Paging`. Returns None
+    /// if 'gpa' falls in a hole in the memory map, and an Error if the memory
+    /// map couldn't be read.
+    pub fn mapping_for_gpa(&self, gpa: u64) -> Result<Option<MemMapping>, Error> {
+        for map in self.memory_map() {
+            let map = map?;
+            if map.gpa > gpa {
+                break;
+            }
+            if map.contains(gpa) {
+                return Ok(Some(map));
+            }
+        }
+        return Ok(None);
+    }
+
     /// Unmap the memory segment at the guest physical address range [gpa,gpa+len)
     pub fn munmap_memseg(&self, gpa: u64, len: usize) -> Result<bool, Error> {
         // Struct is allocated (and owned) by Rust
@@ -211,6 +304,17 @@ impl VirtualMachine {
         }
     }
 
+    /// Iterate over the memory segments allocated for the VM, in order of
+    /// ascending segment ID. Segment IDs without an allocated segment are
+    /// skipped. As with `memory_map`, a failed query other than reaching the
+    /// maximum segment ID is returned as the last item.
+    pub fn memory_segments(&self) -> MemorySegments<'_> {
+        MemorySegments {
+            vm: self,
+            next_segid: Some(0),
+        }
+    }
+
     fn add_devmem(&self, segid: i32, name: &str, base: u64, len: usize) -> Result<bool, Error> {
         self.alloc_memseg(segid, len, name)?;
         let mapoff = self.get_devmem_offset(segid)?;
@@ -271,6 +375,62 @@ impl VirtualMachine {
 
     }
 
+    /// Reads the guest physical memory at 'gpa' into 'buf'.
+    pub fn read_guest(&self, gpa: u64, buf: &mut [u8]) -> Result<bool, Error> {
+        if buf.is_empty() {
+            return Ok(true);
+        }
+        let mapping = self.map_guest(gpa, buf.len(), libc::PROT_READ)?;
+        mapping.read(gpa, buf)
+    }
+
+    /// Writes 'buf' to the guest physical memory at 'gpa'.
+    pub fn write_guest(&self, gpa: u64, buf: &[u8]) -> Result<bool, Error> {
+        if buf.is_empty() {
+            return Ok(true);
+        }
+        let mapping = self.map_guest(gpa, buf.len(), libc::PROT_WRITE)?;
+        mapping.write(gpa, buf)
+    }
+
+    /// Maps the pages of guest physical memory that hold the 'len' bytes at
+    /// 'gpa' into the host address space, with protection 'prot'. The pages
+    /// stay mapped until the returned `GuestMapping` is dropped, so a series
+    /// of nearby accesses can share one mapping.
+    pub fn map_guest(&self, gpa: u64, len: usize, prot: i32) -> Result<GuestMapping, Error> {
+        if len == 0 {
+            return Err(Error::new(EINVAL));
+        }
+        // The VM device maps guest physical memory at offsets equal to the GPA
+        let page_size = unsafe { sysconf(_SC_PAGESIZE) as u64 };
+        let start = gpa & !(page_size - 1);
+        let end = match gpa.checked_add(len as u64 + page_size - 1) {
+            Some(end) => end & !(page_size - 1),
+            None => return Err(Error::new(EINVAL)),
+        };
+        let map_len = (end - start) as usize;
+        let ptr = unsafe {
+            libc::mmap(
+                null_mut(),
+                map_len,
+                prot,
+                libc::MAP_SHARED,
+                self.vm.as_raw_fd(),
+                start as libc::off_t,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(Error::last());
+        }
+
+        return Ok(GuestMapping {
+            ptr: ptr as *mut u8,
+            gpa: start,
+            len: map_len,
+            prot: prot,
+        });
+    }
+
     /// Gets the map offset for the device memory segment 'segid'.
     ///
     /// Returns Ok containing the offset if successful, and an Error otherwise.
@@ -365,6 +525,17 @@ impl VirtualMachine {
         }
     }
 
+    /// Set a descriptor register on the VCPU from a decoded descriptor
+    pub fn set_segment(&self, vcpu_id: i32, reg: vm_reg_name, desc: &SegmentDescriptor) -> Result<bool, Error> {
+        self.set_desc(vcpu_id, reg, desc.base, desc.limit, desc.access())
+    }
+
+    /// Get a descriptor register on the VCPU as a decoded descriptor
+    pub fn get_segment(&self, vcpu_id: i32, reg: vm_reg_name) -> Result<SegmentDescriptor, Error> {
+        let (base, limit, access) = self.get_desc(vcpu_id, reg)?;
+        Ok(SegmentDescriptor::from_access(base, limit, access))
+    }
+
     /// Set the value of a single register on the VCPU
     pub fn set_register(&self, vcpu_id: i32, reg: vm_reg_name, val: u64) -> Result<bool, Error> {
         // Struct is allocated (and owned) by Rust
@@ -397,6 +568,49 @@ impl VirtualMachine {
         }
     }
 
+    /// Set the values of several registers on the VCPU with a single ioctl
+    pub fn set_registers(&self, vcpu_id: i32, regs: &[(vm_reg_name, u64)]) -> Result<bool, Error> {
+        // Arrays are allocated (and owned) by Rust
+        let regnums: Vec<c_int> = regs.iter().map(|&(reg, _)| reg as c_int).collect();
+        let mut regvals: Vec<u64> = regs.iter().map(|&(_, val)| val).collect();
+
+        // Struct is allocated (and owned) by Rust
+        let regset_data = vm_register_set {
+            cpuid: vcpu_id,
+            count: regs.len() as u32,
+            regnums: regnums.as_ptr(),
+            regvals: regvals.as_mut_ptr(),
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_SET_REGISTER_SET, &regset_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::last());
+        }
+    }
+
+    /// Get the values of several registers on the VCPU with a single ioctl.
+    /// The values are returned in the same order as the requested registers.
+    pub fn get_registers(&self, vcpu_id: i32, regs: &[vm_reg_name]) -> Result<Vec<u64>, Error> {
+        // Arrays are allocated (and owned) by Rust, but the values are modified by C
+        let regnums: Vec<c_int> = regs.iter().map(|&reg| reg as c_int).collect();
+        let mut regvals: Vec<u64> = vec![0; regs.len()];
+
+        // Struct is allocated (and owned) by Rust
+        let regset_data = vm_register_set {
+            cpuid: vcpu_id,
+            count: regs.len() as u32,
+            regnums: regnums.as_ptr(),
+            regvals: regvals.as_mut_ptr(),
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_GET_REGISTER_SET, &regset_data) };
+        if result == 0 {
+            return Ok(regvals);
+        } else {
+            return Err(Error::last());
+        }
+    }
+
     pub fn rtc_write(&self, offset: i32, value: u8) -> Result<bool, Error> {
         // Struct is allocated (and owned) by Rust
         let rtc_data = vm_rtc_data {
@@ -480,16 +694,57 @@ impl VirtualMachine {
         }
     }
 
-    /// Gets current stats for a CPUs on the VirtualMachine.
-    pub fn get_stats(&self, vcpu_id: i32) -> Result<i32, Error> {
+    /// Gets current stats for a CPU on the VirtualMachine. Each counter is
+    /// paired with its name, which is fetched from the kernel the first time
+    /// it is seen and cached for the lifetime of the VirtualMachine.
+    pub fn get_stats(&self, vcpu_id: i32) -> Result<VmStats, Error> {
         // Struct is allocated (and owned) by Rust, but modified by C
         let mut stats_data = vm_stats {
             cpuid: vcpu_id,
             ..Default::default()
         };
         let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_STATS_IOC, &mut stats_data) };
+        if result != 0 {
+            return Err(Error::last());
+        }
+
+        let num_entries = stats_data.num_entries as usize;
+        if num_entries > stats_data.statbuf.len() {
+            return Err(Error::new(EINVAL));
+        }
+
+        let mut names = match self.stat_names.lock() {
+            Ok(names) => names,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        while names.len() < num_entries {
+            let name = self.get_stat_desc(names.len() as i32)?;
+            names.push(name);
+        }
+
+        let entries = names.iter().cloned().zip(stats_data.statbuf[..num_entries].iter().cloned()).collect();
+        let timestamp = Duration::new(stats_data.tv.tv_sec as u64, (stats_data.tv.tv_usec * 1000) as u32);
+        Ok(VmStats {
+            timestamp: timestamp,
+            entries: entries,
+        })
+    }
+
+    /// Gets the name of the statistics counter at 'index'.
+    fn get_stat_desc(&self, index: i32) -> Result<String, Error> {
+        // Struct is allocated (and owned) by Rust, but modified by C
+        let mut desc_data = vm_stat_desc {
+            index: index,
+            ..Default::default()
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_STAT_DESC, &mut desc_data) };
         if result == 0 {
-            return Ok(stats_data.num_entries);
+            // The kernel always NUL-terminates the description, but make sure
+            // we never read past the end of the array.
+            let last = desc_data.desc.len() - 1;
+            desc_data.desc[last] = 0;
+            let r_desc = unsafe { CStr::from_ptr(desc_data.desc.as_ptr()) };
+            return Ok(r_desc.to_string_lossy().into_owned());
         } else {
             return Err(Error::last());
         }
@@ -507,6 +762,28 @@ impl VirtualMachine {
         }
     }
 
+    /// Gets the set of Virtual CPUs on the VirtualMachine that are in the
+    /// state identified by 'kind'.
+    pub fn get_cpuset(&self, kind: CpuSetKind) -> Result<CpuSet, Error> {
+        let which = match kind {
+            CpuSetKind::Active => VM_ACTIVE_CPUS,
+            CpuSetKind::Suspended => VM_SUSPENDED_CPUS,
+            CpuSetKind::Debug => VM_DEBUG_CPUS,
+        };
+
+        // Bitmap is allocated (and owned) by Rust, but modified by C
+        let mut cpus = CpuSet::default();
+
+        // Struct is allocated (and owned) by Rust
+        let cpuset_data = cpus.vm_cpuset(which);
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_GET_CPUSET, &cpuset_data) };
+        if result == 0 {
+            return Ok(cpus);
+        } else {
+            return Err(Error::last());
+        }
+    }
+
     pub fn set_x2apic_state(&self, vcpu_id: i32, enable: bool) -> Result<bool, Error> {
         let state = match enable {
             true => x2apic_state::X2APIC_ENABLED,
@@ -547,64 +824,87 @@ impl VirtualMachine {
     /// From Intel Vol 3a:
     /// Table 9-1. IA-32 Processor States Following Power-up, Reset or INIT
     pub fn vcpu_reset(&self, vcpu_id: i32) -> Result<bool, Error> {
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RFLAGS, 0x2)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RIP, 0xfff0)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_CR0, CR0_NE)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_CR3, 0)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_CR4, 0)?;
-
         // CS: present, r/w, accessed, 16-bit, byte granularity, usable
-	let cs_base = 0xffff0000;
-	let cs_limit = 0xffff;
-	let cs_access = 0x0093;
-        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_CS, cs_base, cs_limit, cs_access)?;
-
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_CS, 0xf000)?;
-
+        let cs = SegmentDescriptor::real_mode(0xffff0000);
+        self.set_segment(vcpu_id, vm_reg_name::VM_REG_GUEST_CS, &cs)?;
 
         // SS,DS,ES,FS,GS: present, r/w, accessed, 16-bit, byte granularity
-	let desc_base = 0;
-	let desc_limit = 0xffff;
-	let desc_access = 0x0093;
-        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_SS, desc_base, desc_limit, desc_access)?;
-        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_DS, desc_base, desc_limit, desc_access)?;
-        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_ES, desc_base, desc_limit, desc_access)?;
-        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_FS, desc_base, desc_limit, desc_access)?;
-        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_GS, desc_base, desc_limit, desc_access)?;
-
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_SS, 0)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_DS, 0)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_ES, 0)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_FS, 0)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_GS, 0)?;
-
-        // General purpose registers
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RAX, 0)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RBX, 0)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RCX, 0)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RDX, 0xf00)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RSI, 0)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RDI, 0)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RBP, 0)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RSP, 0)?;
-
+        let data = SegmentDescriptor::real_mode(0);
+        self.set_segment(vcpu_id, vm_reg_name::VM_REG_GUEST_SS, &data)?;
+        self.set_segment(vcpu_id, vm_reg_name::VM_REG_GUEST_DS, &data)?;
+        self.set_segment(vcpu_id, vm_reg_name::VM_REG_GUEST_ES, &data)?;
+        self.set_segment(vcpu_id, vm_reg_name::VM_REG_GUEST_FS, &data)?;
+        self.set_segment(vcpu_id, vm_reg_name::VM_REG_GUEST_GS, &data)?;
 
         // GDTR, IDTR
         self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_GDTR, 0, 0xffff, 0)?;
         self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_IDTR, 0, 0xffff, 0)?;
 
-        // TR
-        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_TR, 0, 0, 0x0000008b)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_TR, 0)?;
+        // TR: busy TSS
+        self.set_segment(vcpu_id, vm_reg_name::VM_REG_GUEST_TR, &SegmentDescriptor::tss(0, 0))?;
 
         // LDTR
-        self.set_desc(vcpu_id, vm_reg_name::VM_REG_GUEST_LDTR, 0, 0xffff, 0x00000082)?;
-        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_LDTR, 0)?;
+        self.set_segment(vcpu_id, vm_reg_name::VM_REG_GUEST_LDTR, &SegmentDescriptor::ldt(0, 0xffff))?;
+
+        // All register values are loaded with a single ioctl
+        self.set_registers(vcpu_id, &[
+            (vm_reg_name::VM_REG_GUEST_RFLAGS, 0x2),
+            (vm_reg_name::VM_REG_GUEST_RIP, 0xfff0),
+            (vm_reg_name::VM_REG_GUEST_CR0, CR0_NE),
+            (vm_reg_name::VM_REG_GUEST_CR3, 0),
+            (vm_reg_name::VM_REG_GUEST_CR4, 0),
+
+            // Segment selectors
+            (vm_reg_name::VM_REG_GUEST_CS, 0xf000),
+            (vm_reg_name::VM_REG_GUEST_SS, 0),
+            (vm_reg_name::VM_REG_GUEST_DS, 0),
+            (vm_reg_name::VM_REG_GUEST_ES, 0),
+            (vm_reg_name::VM_REG_GUEST_FS, 0),
+            (vm_reg_name::VM_REG_GUEST_GS, 0),
+            (vm_reg_name::VM_REG_GUEST_TR, 0),
+            (vm_reg_name::VM_REG_GUEST_LDTR, 0),
+
+            // General purpose registers
+            (vm_reg_name::VM_REG_GUEST_RAX, 0),
+            (vm_reg_name::VM_REG_GUEST_RBX, 0),
+            (vm_reg_name::VM_REG_GUEST_RCX, 0),
+            (vm_reg_name::VM_REG_GUEST_RDX, 0xf00),
+            (vm_reg_name::VM_REG_GUEST_RSI, 0),
+            (vm_reg_name::VM_REG_GUEST_RDI, 0),
+            (vm_reg_name::VM_REG_GUEST_RBP, 0),
+            (vm_reg_name::VM_REG_GUEST_RSP, 0),
+        ])?;
 
         Ok(true)
     }
 
-    /// Suspends a Virtual CPU on the VirtualMachine.
+    /// Brings up the application processor (AP) targeted by a
+    /// `VmExit::SpinupAp`, after the guest sent it a Startup IPI. The target
+    /// VCPU is reset, set up to start executing in real mode at the page
+    /// selected by the SIPI vector, and activated.
+    pub fn spinup_ap(&self, exit: &VmExit) -> Result<bool, Error> {
+        let (vcpu_id, rip) = match *exit {
+            VmExit::SpinupAp { vcpu, rip } => (vcpu, rip),
+            _ => return Err(Error::new(EINVAL)),
+        };
+
+        self.vcpu_reset(vcpu_id)?;
+
+        // The SIPI vector is the page number of the start address. The AP
+        // starts with CS.selector = vector << 8 and CS.base = vector << 12.
+        let vector = rip >> 12;
+        let cs = SegmentDescriptor::real_mode(vector << 12);
+        self.set_segment(vcpu_id, vm_reg_name::VM_REG_GUEST_CS, &cs)?;
+        self.set_registers(vcpu_id, &[
+            (vm_reg_name::VM_REG_GUEST_CS, vector << 8),
+            (vm_reg_name::VM_REG_GUEST_RIP, rip & 0xfff),
+        ])?;
+
+        self.activate_vcpu(vcpu_id)
+    }
+
+    /// Suspends a Virtual CPU on the VirtualMachine. A 'vcpu_id' of -1
+    /// suspends all active VCPUs.
     pub fn suspend_vcpu(&self, vcpu_id: i32) -> Result<bool, Error> {
         // Struct is allocated (and owned) by Rust
         let cpu_data = vm_activate_cpu { vcpuid: vcpu_id };
@@ -616,7 +916,8 @@ impl VirtualMachine {
         }
     }
 
-    /// Resumes a Virtual CPU on the VirtualMachine.
+    /// Resumes a Virtual CPU on the VirtualMachine. A 'vcpu_id' of -1
+    /// resumes all suspended VCPUs.
     pub fn resume_vcpu(&self, vcpu_id: i32) -> Result<bool, Error> {
         // Struct is allocated (and owned) by Rust
         let cpu_data = vm_activate_cpu { vcpuid: vcpu_id };
@@ -628,8 +929,9 @@ impl VirtualMachine {
         }
     }
 
-    /// Runs the VirtualMachine, and returns an exit reason.
-    pub fn run(&self, vcpu_id: i32) -> Result<VmExit, Error> {
+    /// Runs a Virtual CPU until it exits, and returns the exit reason with
+    /// the RIP and length of the instruction that caused the exit.
+    pub fn run(&self, vcpu_id: i32) -> Result<VmExitInfo, Error> {
         // Struct is allocated (and owned) by Rust, but modified by C
         let mut run_data = vm_run {
             cpuid: vcpu_id,
@@ -637,131 +939,209 @@ impl VirtualMachine {
         };
         let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_RUN, &mut run_data) };
         if result == 0 {
-            let rip = run_data.vm_exit.rip;
-            println!("RIP after run is {}", rip);
-            let cid = run_data.cpuid;
-            println!("VCPU ID is {}", cid);
-            match run_data.vm_exit.exitcode {
-                vm_exitcode::VM_EXITCODE_INOUT => {
-                    // Safe because the exit code told us which union field to use.
-                    let io = unsafe { run_data.vm_exit.u.inout };
-                    let port = io.port;
-                    let value = io.eax;
-                    let bytes = io.bytes();
-
-                    if io.is_in() {
-                        return Ok(VmExit::IoIn(port, bytes));
-                    } else {
-                        return Ok(VmExit::IoOut(port, bytes, value));
-                    }
-                }
-                vm_exitcode::VM_EXITCODE_INOUT_STR => {
-                    // Safe because the exit code told us which union field to use.
-                    let vis = unsafe { run_data.vm_exit.u.inout_str };
-                    let io = vis.inout;
-                    let port = io.port;
-
-                    if !io.is_string() {
-                        return Err(Error::new(EINVAL));
-                    }
+            let reason = VirtualMachine::decode_exit(&run_data.vm_exit)?;
+            return Ok(VmExitInfo {
+                rip: run_data.vm_exit.rip,
+                inst_length: run_data.vm_exit.inst_length as u64,
+                reason: reason,
+            });
+        } else {
+            return Err(Error::last());
+        }
+    }
 
-                    let mask: u64 = match vis.addrsize {
-                        2 => 0xffff,
-                        4 => 0xffffffff,
-                        8 => 0xffffffffffffffff,
-                        _ => return Err(Error::new(EINVAL))
-                    };
-
-                    let index: u64 = vis.index & mask;
-                    let count: u64 = vis.count & mask;
-
-                    let bytes = io.bytes();
-                    let repeat = io.is_repeat();
-                    if io.is_in() {
-                        return Ok(VmExit::IoInStr(port, bytes, index, count, repeat));
-                    } else {
-                        return Ok(VmExit::IoOutStr(port, bytes, index, count, repeat));
-                    }
-                }
-                vm_exitcode::VM_EXITCODE_VMX => {
-                    let status = unsafe { run_data.vm_exit.u.vmx.status };
-                    let reason = unsafe { run_data.vm_exit.u.vmx.exit_reason };
-                    let qual = unsafe { run_data.vm_exit.u.vmx.exit_qualification };
-                    let inst_type = unsafe { run_data.vm_exit.u.vmx.inst_type };
-                    let inst_error = unsafe { run_data.vm_exit.u.vmx.inst_error };
-                    return Ok(VmExit::Vmx(status, reason, qual, inst_type, inst_error));
-                }
-                vm_exitcode::VM_EXITCODE_BOGUS => {
-                    return Ok(VmExit::Bogus);
-                }
-                vm_exitcode::VM_EXITCODE_RDMSR => {
-                    return Ok(VmExit::RdMsr);
-                }
-                vm_exitcode::VM_EXITCODE_WRMSR => {
-                    return Ok(VmExit::WrMsr);
-                }
-                vm_exitcode::VM_EXITCODE_HLT => {
-                    return Ok(VmExit::Halt);
-                }
-                vm_exitcode::VM_EXITCODE_MTRAP => {
-                    return Ok(VmExit::Mtrap);
-                }
-                vm_exitcode::VM_EXITCODE_PAUSE => {
-                    return Ok(VmExit::Pause);
-                }
-                vm_exitcode::VM_EXITCODE_PAGING => {
-                    return Ok(VmExit::Paging);
-                }
-                vm_exitcode::VM_EXITCODE_INST_EMUL => {
-                    return Ok(VmExit::InstEmul);
-                }
-                vm_exitcode::VM_EXITCODE_SPINUP_AP => {
-                    return Ok(VmExit::SpinupAp);
-                }
-                vm_exitcode::VM_EXITCODE_DEPRECATED1 => {
-                    return Ok(VmExit::Deprecated);
-                }
-                vm_exitcode::VM_EXITCODE_RUNBLOCK => {
-                    return Ok(VmExit::RunBlock);
-                }
-                vm_exitcode::VM_EXITCODE_IOAPIC_EOI => {
-                    let ioapic = unsafe { run_data.vm_exit.u.ioapic_eoi };
-                    return Ok(VmExit::IoapicEoi(ioapic.vector));
-                }
-                vm_exitcode::VM_EXITCODE_SUSPENDED => {
-                    return Ok(VmExit::Suspended);
-                }
-                vm_exitcode::VM_EXITCODE_TASK_SWITCH => {
-                    return Ok(VmExit::TaskSwitch);
-                }
-                vm_exitcode::VM_EXITCODE_MONITOR => {
-                    return Ok(VmExit::Monitor);
-                }
-                vm_exitcode::VM_EXITCODE_MWAIT => {
-                    return Ok(VmExit::Mwait);
-                }
-                vm_exitcode::VM_EXITCODE_SVM => {
-                    let svm = unsafe { run_data.vm_exit.u.svm };
-                    return Ok(VmExit::Svm(svm.exitcode, svm.exitinfo1, svm.exitinfo2));
-                }
-                vm_exitcode::VM_EXITCODE_REQIDLE => {
-                    return Ok(VmExit::ReqIdle);
-                }
-                vm_exitcode::VM_EXITCODE_DEBUG => {
-                    return Ok(VmExit::Debug);
-                }
-                vm_exitcode::VM_EXITCODE_VMINSN => {
-                    return Ok(VmExit::VmInsn);
+    // Maps the exit code and payload of a VM exit to an exit reason.
+    fn decode_exit(exit: &vm_exit) -> Result<VmExit, Error> {
+        match exit.exitcode {
+            vm_exitcode::VM_EXITCODE_INOUT => {
+                // Safe because the exit code told us which union field to use.
+                let io = unsafe { exit.u.inout };
+                let port = io.port;
+                let value = io.eax;
+                let bytes = io.bytes();
+
+                if io.is_in() {
+                    return Ok(VmExit::IoIn { port: port, bytes: bytes });
+                } else {
+                    return Ok(VmExit::IoOut { port: port, bytes: bytes, value: value });
                 }
-                vm_exitcode::VM_EXITCODE_HT => {
-                    return Ok(VmExit::Ht);
+            }
+            vm_exitcode::VM_EXITCODE_INOUT_STR => {
+                // Safe because the exit code told us which union field to use.
+                let vis = unsafe { exit.u.inout_str };
+                let io = vis.inout;
+                let port = io.port;
+
+                if !io.is_string() {
+                    return Err(Error::new(EINVAL));
                 }
-                vm_exitcode::VM_EXITCODE_MAX => {
-                    return Ok(VmExit::Max);
+
+                let mask: u64 = match vis.addrsize {
+                    2 => 0xffff,
+                    4 => 0xffffffff,
+                    8 => 0xffffffffffffffff,
+                    _ => return Err(Error::new(EINVAL))
+                };
+
+                let index: u64 = vis.index & mask;
+                let count: u64 = vis.count & mask;
+
+                let bytes = io.bytes();
+                let repeat = io.is_repeat();
+                if io.is_in() {
+                    return Ok(VmExit::IoInStr {
+                        port: port,
+                        bytes: bytes,
+                        index: index,
+                        count: count,
+                        repeat: repeat,
+                        addrsize: vis.addrsize as u8,
+                        rflags: vis.rflags,
+                        segment: vis.segname,
+                        seg_desc: vis.seg_desc,
+                        paging: vis.paging,
+                    });
+                } else {
+                    return Ok(VmExit::IoOutStr {
+                        port: port,
+                        bytes: bytes,
+                        index: index,
+                        count: count,
+                        repeat: repeat,
+                        addrsize: vis.addrsize as u8,
+                        rflags: vis.rflags,
+                        segment: vis.segname,
+                        seg_desc: vis.seg_desc,
+                        paging: vis.paging,
+                    });
                 }
             }
-        } else {
-            return Err(Error::last());
+            vm_exitcode::VM_EXITCODE_VMX => {
+                let status = unsafe { exit.u.vmx.status };
+                let reason = unsafe { exit.u.vmx.exit_reason };
+                let qualification = unsafe { exit.u.vmx.exit_qualification };
+                let inst_type = unsafe { exit.u.vmx.inst_type };
+                let inst_error = unsafe { exit.u.vmx.inst_error };
+                return Ok(VmExit::Vmx {
+                    status: status,
+                    reason: VmxExitReason::from_exit_reason(reason),
+                    qualification: qualification,
+                    inst_type: inst_type,
+                    inst_error: inst_error,
+                });
+            }
+            vm_exitcode::VM_EXITCODE_BOGUS => {
+                return Ok(VmExit::Bogus);
+            }
+            vm_exitcode::VM_EXITCODE_RDMSR => {
+                // Safe because the exit code told us which union field to use.
+                let msr = unsafe { exit.u.msr };
+                return Ok(VmExit::RdMsr { msr: msr.code });
+            }
+            vm_exitcode::VM_EXITCODE_WRMSR => {
+                // Safe because the exit code told us which union field to use.
+                let msr = unsafe { exit.u.msr };
+                return Ok(VmExit::WrMsr { msr: msr.code, value: msr.wval });
+            }
+            vm_exitcode::VM_EXITCODE_HLT => {
+                // Safe because the exit code told us which union field to use.
+                let hlt = unsafe { exit.u.hlt };
+                return Ok(VmExit::Halt {
+                    rflags: hlt.rflags,
+                    intr_enabled: (hlt.rflags & PSL_I) != 0,
+                });
+            }
+            vm_exitcode::VM_EXITCODE_MTRAP => {
+                return Ok(VmExit::Mtrap);
+            }
+            vm_exitcode::VM_EXITCODE_PAUSE => {
+                return Ok(VmExit::Pause);
+            }
+            vm_exitcode::VM_EXITCODE_PAGING => {
+                // Safe because the exit code told us which union field to use.
+                let paging = unsafe { exit.u.paging };
+                let fault_type = FaultType::from(paging.fault_type);
+                return Ok(VmExit::Paging { gpa: paging.gpa, fault_type: fault_type });
+            }
+            vm_exitcode::VM_EXITCODE_INST_EMUL => {
+                // Safe because the exit code told us which union field to use.
+                let inst_emul = unsafe { exit.u.inst_emul };
+                return Ok(VmExit::InstEmul {
+                    gpa: inst_emul.gpa,
+                    gla: inst_emul.gla,
+                    cs_base: inst_emul.cs_base,
+                    cs_d: inst_emul.cs_d != 0,
+                    paging: inst_emul.paging,
+                    inst: inst_emul.inst_bytes().to_vec(),
+                });
+            }
+            vm_exitcode::VM_EXITCODE_SPINUP_AP => {
+                // Safe because the exit code told us which union field to use.
+                let spinup = unsafe { exit.u.spinup_ap };
+                return Ok(VmExit::SpinupAp { vcpu: spinup.vcpu, rip: spinup.rip });
+            }
+            vm_exitcode::VM_EXITCODE_DEPRECATED1 => {
+                return Ok(VmExit::Deprecated);
+            }
+            vm_exitcode::VM_EXITCODE_RUNBLOCK => {
+                return Ok(VmExit::RunBlock);
+            }
+            vm_exitcode::VM_EXITCODE_IOAPIC_EOI => {
+                let ioapic = unsafe { exit.u.ioapic_eoi };
+                return Ok(VmExit::IoapicEoi(ioapic.vector));
+            }
+            vm_exitcode::VM_EXITCODE_SUSPENDED => {
+                // Safe because the exit code told us which union field to use.
+                let suspended = unsafe { exit.u.suspended };
+                return Ok(VmExit::Suspended(SuspendReason::from_how(suspended.how)));
+            }
+            vm_exitcode::VM_EXITCODE_TASK_SWITCH => {
+                // Safe because the exit code told us which union field to use.
+                let ts = unsafe { exit.u.task_switch };
+                let reason = match ts.reason {
+                    task_switch_reason::TSR_CALL => TaskSwitchReason::Call,
+                    task_switch_reason::TSR_IRET => TaskSwitchReason::Iret,
+                    task_switch_reason::TSR_JMP => TaskSwitchReason::Jmp,
+                    task_switch_reason::TSR_IDT_GATE => TaskSwitchReason::IdtGate,
+                };
+                return Ok(VmExit::TaskSwitch {
+                    tsssel: ts.tsssel,
+                    ext: ts.ext != 0,
+                    errcode: ts.errcode,
+                    errcode_valid: ts.errcode_valid != 0,
+                    reason: reason,
+                    paging: ts.paging,
+                });
+            }
+            vm_exitcode::VM_EXITCODE_MONITOR => {
+                return Ok(VmExit::Monitor);
+            }
+            vm_exitcode::VM_EXITCODE_MWAIT => {
+                return Ok(VmExit::Mwait);
+            }
+            vm_exitcode::VM_EXITCODE_SVM => {
+                let svm = unsafe { exit.u.svm };
+                return Ok(VmExit::Svm {
+                    code: SvmExitCode::from_code(svm.exitcode),
+                    exitinfo1: svm.exitinfo1,
+                    exitinfo2: svm.exitinfo2,
+                });
+            }
+            vm_exitcode::VM_EXITCODE_REQIDLE => {
+                return Ok(VmExit::ReqIdle);
+            }
+            vm_exitcode::VM_EXITCODE_DEBUG => {
+                return Ok(VmExit::Debug);
+            }
+            vm_exitcode::VM_EXITCODE_VMINSN => {
+                return Ok(VmExit::VmInsn);
+            }
+            vm_exitcode::VM_EXITCODE_HT => {
+                return Ok(VmExit::Ht);
+            }
+            vm_exitcode::VM_EXITCODE_MAX => {
+                return Ok(VmExit::Max);
+            }
         }
     }
 
@@ -819,6 +1199,29 @@ impl VirtualMachine {
         }
     }
 
+    /// Acquires and immediately releases the write lock on the VM. This waits
+    /// for every in-flight ioctl on the VM, including those running VCPUs, to
+    /// complete before returning.
+    pub fn wrlock_cycle(&self) -> Result<i32, Error> {
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_WRLOCK_CYCLE) };
+        if result == 0 {
+            return Ok(result);
+        } else {
+            return Err(Error::last());
+        }
+    }
+
+    /// Quiesces the VirtualMachine before changes to the memory map or
+    /// teardown. All VCPUs are suspended, so they exit the guest and won't
+    /// re-enter it, and then in-flight VCPU ioctls are drained with
+    /// `wrlock_cycle`. Use `resume_vcpu(-1)` to let the VCPUs run again.
+    pub fn quiesce(&self) -> Result<bool, Error> {
+        // A VCPU ID of -1 suspends all active VCPUs
+        self.suspend_vcpu(-1)?;
+        self.wrlock_cycle()?;
+        Ok(true)
+    }
+
     /// Get the value of an optional capability on the VCPU
     pub fn get_capability(&self, vcpu_id: i32, cap: vm_cap_type) -> Result<i32, Error> {
         // Struct is allocated (and owned) by Rust, but modified by C
@@ -915,6 +1318,33 @@ impl VirtualMachine {
         }
     }
 
+    /// Inject a general protection fault (#GP) with an error code of 0 on the
+    /// VCPU. The faulting instruction is restarted, so RIP is left pointing
+    /// at it. This is the usual response to an access to an unknown MSR.
+    pub fn inject_gp(&self, vcpu_id: i32) -> Result<bool, Error> {
+        self.inject_exception(vcpu_id, IDT_GP, 1, 0, 1)
+    }
+
+    /// Complete an emulated RDMSR on the VCPU after a `VmExit::RdMsr`, by
+    /// loading 'value' into EDX:EAX and advancing RIP past the instruction,
+    /// which is 'inst_length' bytes long (2 for the usual encoding).
+    pub fn complete_rdmsr(&self, vcpu_id: i32, value: u64, inst_length: u64) -> Result<bool, Error> {
+        let rip = self.get_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RIP)?;
+        self.set_registers(vcpu_id, &[
+            (vm_reg_name::VM_REG_GUEST_RAX, value & 0xffffffff),
+            (vm_reg_name::VM_REG_GUEST_RDX, value >> 32),
+            (vm_reg_name::VM_REG_GUEST_RIP, rip + inst_length),
+        ])
+    }
+
+    /// Complete an emulated WRMSR on the VCPU after a `VmExit::WrMsr`, by
+    /// advancing RIP past the instruction, which is 'inst_length' bytes long
+    /// (2 for the usual encoding).
+    pub fn complete_wrmsr(&self, vcpu_id: i32, inst_length: u64) -> Result<bool, Error> {
+        let rip = self.get_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RIP)?;
+        self.set_register(vcpu_id, vm_reg_name::VM_REG_GUEST_RIP, rip + inst_length)
+    }
+
     /// Signal to the Local Advanced Programmable Interrupt Controller (LAPIC)
     /// that an interrupt request (IRQ) at 'vector' needs to be sent to the VCPU
     /// identified by 'vcpu_id'. The state of the interrupt request is recorded in
@@ -1009,6 +1439,87 @@ impl VirtualMachine {
         }
     }
 
+    /// Assert a legacy ISA interrupt request, driving both the 8259 PIC pin
+    /// 'atpic_irq' and the I/O APIC pin 'ioapic_irq'. Either pin can be -1 if
+    /// the interrupt is not routed to that controller.
+    pub fn isa_assert_irq(&self, atpic_irq: i32, ioapic_irq: i32) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let irq_data = vm_isa_irq {
+            atpic_irq: atpic_irq,
+            ioapic_irq: ioapic_irq,
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_ISA_ASSERT_IRQ, &irq_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::last());
+        }
+    }
+
+    /// Deassert a legacy ISA interrupt request on the 8259 PIC pin
+    /// 'atpic_irq' and the I/O APIC pin 'ioapic_irq'.
+    pub fn isa_deassert_irq(&self, atpic_irq: i32, ioapic_irq: i32) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let irq_data = vm_isa_irq {
+            atpic_irq: atpic_irq,
+            ioapic_irq: ioapic_irq,
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_ISA_DEASSERT_IRQ, &irq_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::last());
+        }
+    }
+
+    /// Assert and then deassert (a "pulse") a legacy ISA interrupt request on
+    /// the 8259 PIC pin 'atpic_irq' and the I/O APIC pin 'ioapic_irq'.
+    pub fn isa_pulse_irq(&self, atpic_irq: i32, ioapic_irq: i32) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let irq_data = vm_isa_irq {
+            atpic_irq: atpic_irq,
+            ioapic_irq: ioapic_irq,
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_ISA_PULSE_IRQ, &irq_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::last());
+        }
+    }
+
+    /// Set the trigger mode of the 8259 PIC pin 'atpic_irq'.
+    pub fn isa_set_irq_trigger(&self, atpic_irq: i32, trigger: Trigger) -> Result<bool, Error> {
+        let trigger = match trigger {
+            Trigger::Edge => vm_intr_trigger::EDGE_TRIGGER,
+            Trigger::Level => vm_intr_trigger::LEVEL_TRIGGER,
+        };
+
+        // Struct is allocated (and owned) by Rust
+        let trigger_data = vm_isa_irq_trigger {
+            atpic_irq: atpic_irq,
+            trigger: trigger,
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_ISA_SET_IRQ_TRIGGER, &trigger_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::last());
+        }
+    }
+
+    /// Get the capabilities of the in-kernel High Precision Event Timer (HPET)
+    pub fn hpet_capabilities(&self) -> Result<HpetCapabilities, Error> {
+        // Struct is allocated (and owned) by Rust, but modified by C
+        let mut hpet_data = vm_hpet_cap::default();
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_GET_HPET_CAPABILITIES, &mut hpet_data) };
+        if result == 0 {
+            return Ok(HpetCapabilities::from(hpet_data.capabilities));
+        } else {
+            return Err(Error::last());
+        }
+    }
+
     /// Get the I/O APIC pincount for the VM
     pub fn ioapic_pincount(&self) -> Result<i32, Error> {
         // Integer is allocated (and owned) by Rust, but modified by C
@@ -1021,6 +1532,109 @@ impl VirtualMachine {
         }
     }
 
+    /// Assign the host PCI device opened as 'pptfd' to the VM.
+    pub fn bind_pptdev(&self, pptfd: i32) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let ppt_data = vm_pptdev {
+            pptfd: pptfd,
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_BIND_PPTDEV, &ppt_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::last());
+        }
+    }
+
+    /// Release the host PCI device opened as 'pptfd' from the VM.
+    pub fn unbind_pptdev(&self, pptfd: i32) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let ppt_data = vm_pptdev {
+            pptfd: pptfd,
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_UNBIND_PPTDEV, &ppt_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::last());
+        }
+    }
+
+    /// Map the host physical address range [hpa,hpa+len) of a BAR on the
+    /// passthrough device 'pptfd' into the guest address space at 'gpa'.
+    pub fn map_pptdev_mmio(&self, pptfd: i32, gpa: u64, len: usize, hpa: u64) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let mmio_data = vm_pptdev_mmio {
+            pptfd: pptfd,
+            gpa: gpa,
+            hpa: hpa,
+            len: len,
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_MAP_PPTDEV_MMIO, &mmio_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::last());
+        }
+    }
+
+    /// Configure 'numvec' MSI vectors on the passthrough device 'pptfd' to be
+    /// delivered to the guest with the message address 'addr' and data 'msg'.
+    /// Setting 'numvec' to 0 disables MSI on the device.
+    pub fn setup_pptdev_msi(&self, vcpu_id: i32, pptfd: i32, addr: u64, msg: u64, numvec: i32) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let msi_data = vm_pptdev_msi {
+            vcpu: vcpu_id,
+            pptfd: pptfd,
+            numvec: numvec,
+            msg: msg,
+            addr: addr,
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_PPTDEV_MSI, &msi_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::last());
+        }
+    }
+
+    /// Configure the MSI-X table entry 'idx' on the passthrough device 'pptfd'
+    /// to be delivered to the guest with the message address 'addr' and data
+    /// 'msg'. The entry is masked if bit 0 of 'vector_control' is set.
+    pub fn setup_pptdev_msix(&self, vcpu_id: i32, pptfd: i32, idx: i32, addr: u64, msg: u64, vector_control: u32) -> Result<bool, Error> {
+        // Struct is allocated (and owned) by Rust
+        let msix_data = vm_pptdev_msix {
+            vcpu: vcpu_id,
+            pptfd: pptfd,
+            idx: idx,
+            msg: msg,
+            vector_control: vector_control,
+            addr: addr,
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_PPTDEV_MSIX, &msix_data) };
+        if result == 0 {
+            return Ok(true);
+        } else {
+            return Err(Error::last());
+        }
+    }
+
+    /// Get the number of MSI and MSI-X vectors supported by the passthrough
+    /// device 'pptfd', as a tuple of (msi_limit, msix_limit).
+    pub fn get_pptdev_limits(&self, pptfd: i32) -> Result<(i32, i32), Error> {
+        // Struct is allocated (and owned) by Rust, but modified by C
+        let mut limits_data = vm_pptdev_limits {
+            pptfd: pptfd,
+            ..Default::default()
+        };
+        let result = unsafe { ioctl(self.vm.as_raw_fd(), VM_GET_PPTDEV_LIMITS, &mut limits_data) };
+        if result == 0 {
+            return Ok((limits_data.msi_limit, limits_data.msix_limit));
+        } else {
+            return Err(Error::last());
+        }
+    }
+
     /// Restart the current instruction on the VCPU
     pub fn restart_instruction(&self, vcpu_id: i32) -> Result<bool, Error> {
         // Integer is allocated (and owned) by Rust
@@ -1048,6 +1662,11 @@ enum vm_mmap_style {
 //const VM_MEM_F_INCORE: i32 = 0x01;    // include guest memory in core file
 const VM_MEM_F_WIRED: i32 = 0x02;	// guest memory is wired
 
+// Access types ('vm_prot_t') reported in the 'fault_type' of a paging exit.
+//const VM_PROT_READ: i32 = 0x01;
+const VM_PROT_WRITE: i32 = 0x02;
+const VM_PROT_EXECUTE: i32 = 0x04;
+
 /// Identifiers for memory segments, both system memory and devmem segments.
 #[repr(C)]
 #[allow(non_camel_case_types, unused)]
@@ -1059,37 +1678,811 @@ pub enum MemSegId{
         VM_FRAMEBUFFER = 3,
 }
 
+const CPUSET_BITS: usize = c_ulong::BITS as usize;
+const CPUSET_WORDS: usize = VM_MAXCPU.div_ceil(CPUSET_BITS);
+
+// Size of the set passed to VM_GET_CPUSET. The kernel copies out
+// howmany(VM_MAXCPU, NBBY) bytes, and rejects larger sizes with ERANGE,
+// so this can be smaller than the whole words of 'CpuSet::bits'.
+const CPUSET_SIZE: usize = VM_MAXCPU.div_ceil(NBBY);
+
+/// Virtual CPU states that can be queried with `get_cpuset`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CpuSetKind {
+    /// VCPUs that have been activated
+    Active,
+    /// VCPUs that have been suspended
+    Suspended,
+    /// VCPUs that have been stopped for debugging
+    Debug,
+}
+
+/// A set of Virtual CPU IDs, as returned by `get_cpuset`.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct CpuSet {
+    bits: [c_ulong; CPUSET_WORDS],
+}
+
+impl CpuSet {
+    /// Returns true if the VCPU identified by 'vcpu_id' is in the set.
+    pub fn contains(&self, vcpu_id: i32) -> bool {
+        if vcpu_id < 0 || vcpu_id as usize >= CPUSET_WORDS * CPUSET_BITS {
+            return false;
+        }
+        let id = vcpu_id as usize;
+        (self.bits[id / CPUSET_BITS] & (1 << (id % CPUSET_BITS))) != 0
+    }
+
+    /// Returns the number of VCPUs in the set.
+    pub fn len(&self) -> usize {
+        self.bits.iter().map(|word| word.count_ones() as usize).sum()
+    }
+
+    /// Returns true if the set contains no VCPUs.
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|&word| word == 0)
+    }
+
+    /// Iterates over the IDs of the VCPUs in the set, in ascending order.
+    pub fn iter(&self) -> CpuSetIter {
+        CpuSetIter {
+            set: *self,
+            next: 0,
+        }
+    }
+
+    // Returns the VM_GET_CPUSET argument that copies the set 'which' into
+    // this set.
+    fn vm_cpuset(&mut self, which: c_int) -> vm_cpuset {
+        vm_cpuset {
+            which: which,
+            cpusetsize: CPUSET_SIZE as c_int,
+            cpus: self.bits.as_mut_ptr(),
+        }
+    }
+}
+
+impl IntoIterator for &CpuSet {
+    type Item = i32;
+    type IntoIter = CpuSetIter;
+
+    fn into_iter(self) -> CpuSetIter {
+        self.iter()
+    }
+}
+
+/// Iterator over the VCPU IDs in a `CpuSet`.
+pub struct CpuSetIter {
+    set: CpuSet,
+    next: usize,
+}
+
+impl Iterator for CpuSetIter {
+    type Item = i32;
+
+    fn next(&mut self) -> Option<i32> {
+        while self.next < CPUSET_WORDS * CPUSET_BITS {
+            let id = self.next as i32;
+            self.next += 1;
+            if self.set.contains(id) {
+                return Some(id);
+            }
+        }
+        None
+    }
+}
+
+/// Trigger modes for legacy ISA interrupts.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+/// Capabilities of the High Precision Event Timer, decoded from the lower
+/// 32 bits of the General Capabilities and ID Register.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct HpetCapabilities {
+    /// The raw register value, as used for the ACPI HPET table
+    pub raw: u32,
+    /// PCI vendor ID of the implementation
+    pub vendor_id: u16,
+    /// Implementation revision
+    pub revision: u8,
+    /// Width of the main counter in bits, either 32 or 64
+    pub counter_width: u8,
+    /// Number of timers (comparators)
+    pub num_timers: u8,
+    /// Supports the legacy replacement interrupt route
+    pub legacy_route: bool,
+}
+
+impl From<u32> for HpetCapabilities {
+    fn from(raw: u32) -> HpetCapabilities {
+        HpetCapabilities {
+            raw: raw,
+            vendor_id: (raw >> 16) as u16,
+            revision: (raw & 0xff) as u8,
+            counter_width: if (raw & (1 << 13)) != 0 { 64 } else { 32 },
+            num_timers: (((raw >> 8) & 0x1f) + 1) as u8,
+            legacy_route: (raw & (1 << 15)) != 0,
+        }
+    }
+}
+
+/// A sample of the statistics counters for a single VCPU.
+#[derive(Debug, Clone)]
+pub struct VmStats {
+    /// Time at which the kernel took the sample.
+    pub timestamp: Duration,
+    /// Counter names and values, in the order the kernel reports them.
+    pub entries: Vec<(String, u64)>,
+}
+
+impl VmStats {
+    /// Returns the value of the counter called 'name', if present.
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.entries.iter().find(|(n, _)| n == name).map(|&(_, value)| value)
+    }
+
+    /// Computes the per-second rate of change of each counter since an
+    /// earlier sample 'prev' of the same VCPU. Counters that are missing from
+    /// 'prev' are skipped. If no time has elapsed between the samples, every
+    /// rate is zero.
+    pub fn delta(&self, prev: &VmStats) -> Vec<(String, f64)> {
+        let elapsed = match self.timestamp.checked_sub(prev.timestamp) {
+            Some(d) => d.as_secs_f64(),
+            None => 0.0,
+        };
+
+        let mut rates = Vec::with_capacity(self.entries.len());
+        for (name, value) in self.entries.iter() {
+            let prev_value = match prev.get(name) {
+                Some(v) => v,
+                None => continue,
+            };
+            let rate = if elapsed > 0.0 {
+                value.saturating_sub(prev_value) as f64 / elapsed
+            } else {
+                0.0
+            };
+            rates.push((name.clone(), rate));
+        }
+        rates
+    }
+}
+
+/// Result of translating a guest linear address with `gla2gpa` or
+/// `gla2gpa_nofault`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Translation {
+    /// The linear address maps to this guest physical address.
+    Gpa(u64),
+    /// The guest page tables don't permit the access. For `gla2gpa`, the
+    /// kernel has already injected the fault into the VCPU.
+    Fault,
+}
+
+/// A mapping of a memory segment into the guest physical address space.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MemMapping {
+    /// Guest physical address of the start of the mapping
+    pub gpa: u64,
+    /// Length of the mapping in bytes
+    pub len: usize,
+    /// Memory segment mapped
+    pub segid: i32,
+    /// Offset into the memory segment of the start of the mapping
+    pub segoff: i64,
+    /// Guest access permissions (PROT_READ, PROT_WRITE, PROT_EXEC)
+    pub prot: i32,
+    /// Mapping flags (VM_MEMMAP_F_*)
+    pub flags: i32,
+}
+
+impl MemMapping {
+    /// Returns true if 'gpa' falls within the mapping.
+    pub fn contains(&self, gpa: u64) -> bool {
+        gpa >= self.gpa && (gpa - self.gpa) < self.len as u64
+    }
+
+    /// Returns the offset into the memory segment that backs 'gpa', or None
+    /// if 'gpa' falls outside the mapping.
+    pub fn segment_offset(&self, gpa: u64) -> Option<i64> {
+        if self.contains(gpa) {
+            Some(self.segoff + (gpa - self.gpa) as i64)
+        } else {
+            None
+        }
+    }
+}
+
+/// Guest physical memory mapped into the host address space by `map_guest`,
+/// which is unmapped when dropped.
+pub struct GuestMapping {
+    ptr: *mut u8,
+    gpa: u64,
+    len: usize,
+    prot: i32,
+}
+
+impl GuestMapping {
+    /// Returns true if the 'len' bytes at 'gpa' fall within the mapping, and
+    /// it was mapped with all of the protection bits in 'prot'.
+    pub fn contains(&self, gpa: u64, len: usize, prot: i32) -> bool {
+        gpa >= self.gpa && (gpa - self.gpa).saturating_add(len as u64) <= self.len as u64 &&
+            (self.prot & prot) == prot
+    }
+
+    /// Reads the guest physical memory at 'gpa' into 'buf'.
+    pub fn read(&self, gpa: u64, buf: &mut [u8]) -> Result<bool, Error> {
+        if !self.contains(gpa, buf.len(), libc::PROT_READ) {
+            return Err(Error::new(EINVAL));
+        }
+        // Safe because the mapping covers 'buf.len()' bytes from 'gpa'
+        unsafe {
+            let src = self.ptr.add((gpa - self.gpa) as usize);
+            std::ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len());
+        }
+        return Ok(true);
+    }
+
+    /// Writes 'buf' to the guest physical memory at 'gpa'.
+    pub fn write(&self, gpa: u64, buf: &[u8]) -> Result<bool, Error> {
+        if !self.contains(gpa, buf.len(), libc::PROT_WRITE) {
+            return Err(Error::new(EINVAL));
+        }
+        // Safe because the mapping covers 'buf.len()' bytes from 'gpa'
+        unsafe {
+            let dst = self.ptr.add((gpa - self.gpa) as usize);
+            std::ptr::copy_nonoverlapping(buf.as_ptr(), dst, buf.len());
+        }
+        return Ok(true);
+    }
+}
+
+impl Drop for GuestMapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut c_void, self.len) };
+    }
+}
+
+/// Iterator over the guest physical memory map, returned by `memory_map`.
+pub struct MemoryMap<'a> {
+    vm: &'a VirtualMachine,
+    next_gpa: Option<u64>,
+}
+
+impl<'a> Iterator for MemoryMap<'a> {
+    type Item = Result<MemMapping, Error>;
+
+    fn next(&mut self) -> Option<Result<MemMapping, Error>> {
+        let gpa = self.next_gpa?;
+        match self.vm.mmap_getnext(gpa) {
+            Ok(map) => {
+                // Continue the search after the end of this mapping, and stop
+                // if it reaches the end of the address space.
+                self.next_gpa = map.gpa.checked_add(std::cmp::max(map.len as u64, 1));
+                Some(Ok(MemMapping {
+                    gpa: map.gpa,
+                    len: map.len,
+                    segid: map.segid,
+                    segoff: map.segoff,
+                    prot: map.prot,
+                    flags: map.flags,
+                }))
+            }
+            Err(e) => {
+                self.next_gpa = None;
+                if e.errno() == ENOENT {
+                    // No more mappings at or above 'gpa'
+                    None
+                } else {
+                    Some(Err(e))
+                }
+            }
+        }
+    }
+}
+
+/// A memory segment allocated for the VM.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemSegment {
+    /// Memory segment ID
+    pub segid: i32,
+    /// Length of the segment in bytes
+    pub len: usize,
+    /// Device name of the segment, empty for system memory
+    pub name: String,
+}
+
+/// Iterator over the allocated memory segments, returned by `memory_segments`.
+pub struct MemorySegments<'a> {
+    vm: &'a VirtualMachine,
+    next_segid: Option<i32>,
+}
+
+impl<'a> Iterator for MemorySegments<'a> {
+    type Item = Result<MemSegment, Error>;
+
+    fn next(&mut self) -> Option<Result<MemSegment, Error>> {
+        loop {
+            let segid = self.next_segid?;
+            self.next_segid = segid.checked_add(1);
+            match self.vm.get_memseg(segid) {
+                Ok(mut seg) => {
+                    if seg.len == 0 {
+                        // No segment allocated with this ID
+                        continue;
+                    }
+                    let last = seg.name.len() - 1;
+                    seg.name[last] = 0;
+                    let r_name = unsafe { CStr::from_ptr(seg.name.as_ptr()) };
+                    return Some(Ok(MemSegment {
+                        segid: seg.segid,
+                        len: seg.len,
+                        name: r_name.to_string_lossy().into_owned(),
+                    }));
+                }
+                Err(e) => {
+                    self.next_segid = None;
+                    if e.errno() == EINVAL {
+                        // The segment ID is beyond the maximum for the VM
+                        return None;
+                    }
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Type of access that caused a nested page fault, reported by
+/// `VmExit::Paging`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FaultType {
+    Read,
+    Write,
+    Execute,
+}
+
+impl From<i32> for FaultType {
+    fn from(fault_type: i32) -> FaultType {
+        if (fault_type & VM_PROT_WRITE) != 0 {
+            FaultType::Write
+        } else if (fault_type & VM_PROT_EXECUTE) != 0 {
+            FaultType::Execute
+        } else {
+            FaultType::Read
+        }
+    }
+}
+
+/// Reasons for a virtual machine to be suspended, reported by
+/// `VmExit::Suspended`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SuspendReason {
+    /// The guest requested a reset (reboot)
+    Reset,
+    /// The guest requested to be powered off
+    PowerOff,
+    /// The guest halted with interrupts disabled on all VCPUs
+    Halt,
+    /// A VCPU triple faulted
+    TripleFault,
+    /// A raw `vm_suspend_how` value without a defined reason
+    Unknown(i32),
+}
+
+impl SuspendReason {
+    fn from_how(how: vm_suspend_how) -> SuspendReason {
+        match how {
+            vm_suspend_how::VM_SUSPEND_RESET => SuspendReason::Reset,
+            vm_suspend_how::VM_SUSPEND_POWEROFF => SuspendReason::PowerOff,
+            vm_suspend_how::VM_SUSPEND_HALT => SuspendReason::Halt,
+            vm_suspend_how::VM_SUSPEND_TRIPLEFAULT => SuspendReason::TripleFault,
+            vm_suspend_how::VM_SUSPEND_NONE |
+            vm_suspend_how::VM_SUSPEND_LAST => SuspendReason::Unknown(how as i32),
+        }
+    }
+}
+
+/// Reasons for a hardware task switch, reported by `VmExit::TaskSwitch`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TaskSwitchReason {
+    /// CALL through a task gate or to a TSS descriptor
+    Call,
+    /// IRET with the NT flag set
+    Iret,
+    /// JMP through a task gate or to a TSS descriptor
+    Jmp,
+    /// Interrupt or exception through a task gate in the IDT
+    IdtGate,
+}
+
+/// A virtual machine exit, returned by `run`.
+#[derive(Debug)]
+pub struct VmExitInfo {
+    /// RIP of the instruction that caused the exit
+    pub rip: u64,
+    /// Length of the instruction that caused the exit, 0 if unknown
+    pub inst_length: u64,
+    pub reason: VmExit,
+}
+
 /// Reasons for virtual machine exits.
 ///
 /// The exit reasons are mapped to the `VM_EXIT_*` defines in `machine/vmm.h`.
 ///
 #[derive(Debug)]
 pub enum VmExit {
-    IoIn(u16 /* port */, u16 /* bytes */),
-    IoOut(u16 /* port */, u16 /* bytes */, u32 /* value */),
-    IoInStr(u16 /* port */, u16 /* bytes */, u64 /* index */, u64 /* count */, bool /* repeat */),
-    IoOutStr(u16 /* port */, u16 /* bytes */, u64 /* index */, u64 /* count */, bool /* repeat */),
-    Vmx(i32 /* status */, u32 /* exit reason */, u64 /* exit qualification */, i32 /* instruction type */, i32 /* instruction error */),
+    IoIn { port: u16, bytes: u16 },
+    IoOut { port: u16, bytes: u16, value: u32 },
+    IoInStr {
+        port: u16,
+        bytes: u16,
+        index: u64,                 // offset of the buffer, from RDI
+        count: u64,                 // repeat count, from RCX
+        repeat: bool,               // instruction has a REP prefix
+        addrsize: u8,               // address size in bytes: 2, 4, or 8
+        rflags: u64,
+        segment: vm_reg_name,       // segment of the buffer, always ES
+        seg_desc: seg_desc,         // descriptor of 'segment'
+        paging: vm_guest_paging,    // guest paging state
+    },
+    IoOutStr {
+        port: u16,
+        bytes: u16,
+        index: u64,                 // offset of the buffer, from RSI
+        count: u64,                 // repeat count, from RCX
+        repeat: bool,               // instruction has a REP prefix
+        addrsize: u8,               // address size in bytes: 2, 4, or 8
+        rflags: u64,
+        segment: vm_reg_name,       // segment of the buffer, DS unless overridden
+        seg_desc: seg_desc,         // descriptor of 'segment'
+        paging: vm_guest_paging,    // guest paging state
+    },
+    /// An Intel VMX exit that Bhyve didn't handle. The qualification can be
+    /// decoded with the types in `vmx`, according to the exit reason.
+    Vmx { status: i32, reason: VmxExitReason, qualification: u64, inst_type: i32, inst_error: i32 },
     Bogus,
-    RdMsr,
-    WrMsr,
-    Halt,
+    RdMsr { msr: u32 },
+    WrMsr { msr: u32, value: u64 },
+    Halt { rflags: u64, intr_enabled: bool },
     Mtrap,
     Pause,
-    Paging,
-    InstEmul,
-    SpinupAp,
+    Paging { gpa: u64, fault_type: FaultType },
+    InstEmul {
+        gpa: u64,                   // guest physical address of the access
+        gla: u64,                   // guest linear address of the access
+        cs_base: u64,               // base of the code segment
+        cs_d: bool,                 // CS.D, default operand size is 32-bit
+        paging: vm_guest_paging,    // guest paging state
+        inst: Vec<u8>,              // instruction bytes, empty if not fetched
+    },
+    SpinupAp { vcpu: i32, rip: u64 },
     Deprecated,
     RunBlock,
     IoapicEoi(i32 /* vector */),
-    Suspended,
-    TaskSwitch,
+    Suspended(SuspendReason),
+    TaskSwitch {
+        tsssel: u16,                // new TSS selector
+        ext: bool,                  // task switch due to external event
+        errcode: u32,
+        errcode_valid: bool,        // push 'errcode' on the new stack
+        reason: TaskSwitchReason,
+        paging: vm_guest_paging,    // guest paging state
+    },
     Monitor,
     Mwait,
-    Svm(u64 /* exitcode */, u64 /* exitinfo1 */, u64 /* exitinfo2 */),
+    /// An AMD SVM exit that Bhyve didn't handle. The exit information can be
+    /// decoded with the types in `svm`, according to the exit code.
+    Svm { code: SvmExitCode, exitinfo1: u64, exitinfo2: u64 },
     ReqIdle,
     Debug,
     VmInsn,
     Ht,
     Max,
 }
+
+impl VmExit {
+    /// Describes a VMX or SVM exit that Bhyve didn't handle, in the same
+    /// terms for both vendors. Returns None for any other exit.
+    pub fn unhandled(&self) -> Option<UnhandledExit> {
+        match *self {
+            VmExit::Vmx { status, reason, qualification, .. } => {
+                let kind = if status != 0 {
+                    UnhandledKind::EntryFailure
+                } else {
+                    match reason {
+                        VmxExitReason::TripleFault => UnhandledKind::Shutdown,
+                        VmxExitReason::Cpuid => UnhandledKind::Cpuid,
+                        VmxExitReason::Hlt => UnhandledKind::Hlt,
+                        VmxExitReason::Rdmsr => UnhandledKind::Rdmsr,
+                        VmxExitReason::Wrmsr => UnhandledKind::Wrmsr,
+                        VmxExitReason::IoInstruction => {
+                            let io = IoInstruction::from_qualification(qualification);
+                            UnhandledKind::Io {
+                                port: io.port,
+                                bytes: io.bytes,
+                                is_in: io.is_in,
+                                is_string: io.is_string,
+                            }
+                        }
+                        VmxExitReason::EptViolation => {
+                            let ept = EptViolation::from_qualification(qualification);
+                            // The faulting GPA isn't part of the VMX exit
+                            UnhandledKind::NestedPageFault { gpa: None, fault_type: ept.fault_type() }
+                        }
+                        VmxExitReason::EntryFailGuestState |
+                        VmxExitReason::EntryFailMsrLoading |
+                        VmxExitReason::EntryFailMachineCheck => UnhandledKind::EntryFailure,
+                        _ => UnhandledKind::Other,
+                    }
+                };
+                return Some(UnhandledExit {
+                    vendor: Vendor::Intel,
+                    code: reason.code() as u64,
+                    name: format!("{:?}", reason),
+                    info: qualification,
+                    kind: kind,
+                });
+            }
+            VmExit::Svm { code, exitinfo1, exitinfo2 } => {
+                let kind = match code {
+                    SvmExitCode::Shutdown => UnhandledKind::Shutdown,
+                    SvmExitCode::Cpuid => UnhandledKind::Cpuid,
+                    SvmExitCode::Hlt => UnhandledKind::Hlt,
+                    // EXITINFO1 is 1 for WRMSR and 0 for RDMSR
+                    SvmExitCode::Msr if exitinfo1 & 1 != 0 => UnhandledKind::Wrmsr,
+                    SvmExitCode::Msr => UnhandledKind::Rdmsr,
+                    SvmExitCode::Ioio => {
+                        let io = IoioInfo::from_exitinfo(exitinfo1);
+                        UnhandledKind::Io {
+                            port: io.port,
+                            bytes: io.bytes,
+                            is_in: io.is_in,
+                            is_string: io.is_string,
+                        }
+                    }
+                    SvmExitCode::Npf => {
+                        let npf = NpfInfo::from_exitinfo(exitinfo1, exitinfo2);
+                        UnhandledKind::NestedPageFault { gpa: Some(npf.gpa), fault_type: npf.fault_type() }
+                    }
+                    SvmExitCode::Invalid => UnhandledKind::EntryFailure,
+                    _ => UnhandledKind::Other,
+                };
+                return Some(UnhandledExit {
+                    vendor: Vendor::Amd,
+                    code: code.code(),
+                    name: format!("{:?}", code),
+                    info: exitinfo1,
+                    kind: kind,
+                });
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Hardware virtualization vendors.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Vendor {
+    /// Intel VMX
+    Intel,
+    /// AMD SVM
+    Amd,
+}
+
+impl fmt::Display for Vendor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Vendor::Intel => write!(f, "VMX"),
+            Vendor::Amd => write!(f, "SVM"),
+        }
+    }
+}
+
+/// Vendor-neutral classes of unhandled exits.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum UnhandledKind {
+    /// The guest triple faulted (VMX) or hit a shutdown condition (SVM)
+    Shutdown,
+    /// VM entry failed
+    EntryFailure,
+    Cpuid,
+    Hlt,
+    Rdmsr,
+    Wrmsr,
+    /// Port I/O instruction
+    Io { port: u16, bytes: u8, is_in: bool, is_string: bool },
+    /// Fault in the nested page tables. The GPA is only reported by SVM.
+    NestedPageFault { gpa: Option<u64>, fault_type: FaultType },
+    /// Any other exit, see the vendor exit code
+    Other,
+}
+
+impl fmt::Display for UnhandledKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UnhandledKind::Shutdown => write!(f, "shutdown"),
+            UnhandledKind::EntryFailure => write!(f, "VM entry failure"),
+            UnhandledKind::Cpuid => write!(f, "CPUID"),
+            UnhandledKind::Hlt => write!(f, "HLT"),
+            UnhandledKind::Rdmsr => write!(f, "RDMSR"),
+            UnhandledKind::Wrmsr => write!(f, "WRMSR"),
+            UnhandledKind::Io { port, bytes, is_in, is_string } => {
+                let dir = if is_in { "in" } else { "out" };
+                let string = if is_string { " string" } else { "" };
+                write!(f, "I/O {}{} port={:#x} bytes={}", dir, string, port, bytes)
+            }
+            UnhandledKind::NestedPageFault { gpa: Some(gpa), fault_type } => {
+                write!(f, "nested page fault {:?} gpa={:#x}", fault_type, gpa)
+            }
+            UnhandledKind::NestedPageFault { gpa: None, fault_type } => {
+                write!(f, "nested page fault {:?}", fault_type)
+            }
+            UnhandledKind::Other => write!(f, "other"),
+        }
+    }
+}
+
+/// A vendor-neutral description of a VMX or SVM exit that Bhyve didn't
+/// handle, returned by `VmExit::unhandled`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnhandledExit {
+    pub vendor: Vendor,
+    /// Raw VMX basic exit reason or SVM exit code
+    pub code: u64,
+    /// Name of the vendor exit code
+    pub name: String,
+    /// VMX exit qualification or SVM EXITINFO1
+    pub info: u64,
+    pub kind: UnhandledKind,
+}
+
+impl fmt::Display for UnhandledExit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unhandled {} exit {} ({:#x}): {}, info={:#x}",
+               self.vendor, self.name, self.code, self.kind, self.info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::size_of;
+
+    use crate::vm::*;
+
+    #[test]
+    fn test_cpuset() {
+        let mut set = CpuSet::default();
+        assert!(set.is_empty());
+        assert!(!set.contains(0));
+
+        set.bits[0] = 0b1011;
+        assert!(!set.is_empty());
+        assert_eq!(set.len(), 3);
+        assert!(set.contains(0));
+        assert!(!set.contains(2));
+        assert!(!set.contains(-1));
+        assert!(!set.contains(4096));
+        assert_eq!(set.iter().collect::<Vec<i32>>(), vec![0, 1, 3]);
+    }
+
+    #[test]
+    fn test_cpuset_size() {
+        // howmany(VM_MAXCPU, NBBY), rather than a whole number of words
+        let mut set = CpuSet::default();
+        let cpuset_data = set.vm_cpuset(VM_SUSPENDED_CPUS);
+        assert_eq!(cpuset_data.which, VM_SUSPENDED_CPUS);
+        assert_eq!(cpuset_data.cpusetsize, 4);
+        assert!(size_of::<CpuSet>() >= cpuset_data.cpusetsize as usize);
+    }
+
+    #[test]
+    fn test_suspend_reason() {
+        assert_eq!(SuspendReason::from_how(vm_suspend_how::VM_SUSPEND_RESET), SuspendReason::Reset);
+        assert_eq!(SuspendReason::from_how(vm_suspend_how::VM_SUSPEND_TRIPLEFAULT), SuspendReason::TripleFault);
+        assert_eq!(SuspendReason::from_how(vm_suspend_how::VM_SUSPEND_NONE), SuspendReason::Unknown(0));
+        assert_eq!(SuspendReason::from_how(vm_suspend_how::VM_SUSPEND_LAST), SuspendReason::Unknown(5));
+    }
+
+    #[test]
+    fn test_unhandled_exit() {
+        let vmx = VmExit::Vmx {
+            status: 0,
+            reason: VmxExitReason::IoInstruction,
+            qualification: 0x00800040,
+            inst_type: 0,
+            inst_error: 0,
+        };
+        let svm = VmExit::Svm {
+            code: SvmExitCode::Ioio,
+            exitinfo1: 0x00800010,
+            exitinfo2: 0,
+        };
+        let io = UnhandledKind::Io { port: 0x80, bytes: 1, is_in: false, is_string: false };
+        assert_eq!(vmx.unhandled().unwrap().kind, io);
+        assert_eq!(svm.unhandled().unwrap().kind, io);
+        assert_eq!(format!("{}", svm.unhandled().unwrap()),
+                   "unhandled SVM exit Ioio (0x7b): I/O out port=0x80 bytes=1, info=0x800010");
+
+        let npf = VmExit::Svm {
+            code: SvmExitCode::Npf,
+            exitinfo1: 0x1_0000_0004,
+            exitinfo2: 0xfee0_0000,
+        };
+        assert_eq!(npf.unhandled().unwrap().kind,
+                   UnhandledKind::NestedPageFault { gpa: Some(0xfee0_0000), fault_type: FaultType::Read });
+
+        let shutdown = VmExit::Vmx {
+            status: 0,
+            reason: VmxExitReason::TripleFault,
+            qualification: 0,
+            inst_type: 0,
+            inst_error: 0,
+        };
+        assert_eq!(shutdown.unhandled().unwrap().kind, UnhandledKind::Shutdown);
+        assert!(VmExit::Bogus.unhandled().is_none());
+    }
+
+    #[test]
+    fn test_hpet_capabilities() {
+        // The value reported by the bhyve virtual HPET
+        let cap = HpetCapabilities::from(0x80860701);
+        assert_eq!(cap.vendor_id, 0x8086);
+        assert_eq!(cap.revision, 1);
+        assert_eq!(cap.counter_width, 32);
+        assert_eq!(cap.num_timers, 8);
+        assert_eq!(cap.legacy_route, false);
+
+        let cap = HpetCapabilities::from(0x8086a201);
+        assert_eq!(cap.counter_width, 64);
+        assert_eq!(cap.num_timers, 3);
+        assert_eq!(cap.legacy_route, true);
+    }
+
+    #[test]
+    fn test_mem_mapping() {
+        let map = MemMapping {
+            gpa: 0x100000,
+            len: 0x2000,
+            segid: 0,
+            segoff: 0x100000,
+            prot: 7,
+            flags: 0,
+        };
+        assert!(!map.contains(0xfffff));
+        assert!(map.contains(0x100000));
+        assert!(map.contains(0x101fff));
+        assert!(!map.contains(0x102000));
+        assert_eq!(map.segment_offset(0x101000), Some(0x101000));
+        assert_eq!(map.segment_offset(0x102000), None);
+
+        assert_eq!(FaultType::from(1), FaultType::Read);
+        assert_eq!(FaultType::from(2), FaultType::Write);
+        assert_eq!(FaultType::from(4), FaultType::Execute);
+    }
+
+    #[test]
+    fn test_stats_delta() {
+        let prev = VmStats {
+            timestamp: Duration::new(10, 0),
+            entries: vec![("vm exits".to_string(), 100), ("halts".to_string(), 7)],
+        };
+        let cur = VmStats {
+            timestamp: Duration::new(12, 0),
+            entries: vec![("vm exits".to_string(), 300), ("halts".to_string(), 7), ("new".to_string(), 5)],
+        };
+        assert_eq!(cur.get("halts"), Some(7));
+        assert_eq!(cur.get("missing"), None);
+
+        let rates = cur.delta(&prev);
+        assert_eq!(rates, vec![("vm exits".to_string(), 100.0), ("halts".to_string(), 0.0)]);
+
+        // A sample compared with itself has no elapsed time
+        let rates = cur.delta(&cur);
+        assert!(rates.iter().all(|&(_, r)| r == 0.0));
+    }
+}
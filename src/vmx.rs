@@ -0,0 +1,349 @@
+//! Intel VMX exit reasons and exit qualifications.
+//!
+//! When Bhyve has no better exit code for a VM exit on an Intel host, it
+//! reports the raw VMX exit reason and exit qualification in
+//! `VmExit::Vmx`. The types here decode those values according to the
+//! Intel SDM, Vol. 3C, Appendix C (basic exit reasons) and Section 27.2.1
+//! (exit qualifications).
+
+use crate::vm::{vm_reg_name, FaultType, TaskSwitchReason};
+
+code_enum! {
+    /// Basic VMX exit reasons, from bits 15:0 of the exit reason field.
+    pub enum VmxExitReason: u16 {
+        ExceptionOrNmi = 0,
+        ExternalInterrupt = 1,
+        TripleFault = 2,
+        InitSignal = 3,
+        StartupIpi = 4,
+        IoSmi = 5,
+        OtherSmi = 6,
+        InterruptWindow = 7,
+        NmiWindow = 8,
+        TaskSwitch = 9,
+        Cpuid = 10,
+        Getsec = 11,
+        Hlt = 12,
+        Invd = 13,
+        Invlpg = 14,
+        Rdpmc = 15,
+        Rdtsc = 16,
+        Rsm = 17,
+        Vmcall = 18,
+        Vmclear = 19,
+        Vmlaunch = 20,
+        Vmptrld = 21,
+        Vmptrst = 22,
+        Vmread = 23,
+        Vmresume = 24,
+        Vmwrite = 25,
+        Vmxoff = 26,
+        Vmxon = 27,
+        CrAccess = 28,
+        DrAccess = 29,
+        IoInstruction = 30,
+        Rdmsr = 31,
+        Wrmsr = 32,
+        EntryFailGuestState = 33,
+        EntryFailMsrLoading = 34,
+        Mwait = 36,
+        MonitorTrapFlag = 37,
+        Monitor = 39,
+        Pause = 40,
+        EntryFailMachineCheck = 41,
+        TprBelowThreshold = 43,
+        ApicAccess = 44,
+        VirtualizedEoi = 45,
+        GdtrIdtrAccess = 46,
+        LdtrTrAccess = 47,
+        EptViolation = 48,
+        EptMisconfig = 49,
+        Invept = 50,
+        Rdtscp = 51,
+        PreemptionTimer = 52,
+        Invvpid = 53,
+        Wbinvd = 54,
+        Xsetbv = 55,
+        ApicWrite = 56,
+        Rdrand = 57,
+        Invpcid = 58,
+        Vmfunc = 59,
+        Encls = 60,
+        Rdseed = 61,
+        PmlFull = 62,
+        Xsaves = 63,
+        Xrstors = 64,
+        SppEvent = 66,
+        Umwait = 67,
+        Tpause = 68,
+        Loadiwkey = 69,
+    }
+}
+
+impl VmxExitReason {
+    /// Decodes the full 32-bit exit reason field, ignoring the flag bits
+    /// above the basic exit reason.
+    pub fn from_exit_reason(exit_reason: u32) -> VmxExitReason {
+        VmxExitReason::from_code((exit_reason & 0xffff) as u16)
+    }
+}
+
+/// Access types for control register accesses.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CrAccessType {
+    MovToCr,
+    MovFromCr,
+    Clts,
+    Lmsw,
+}
+
+/// Exit qualification for control register accesses (SDM Table 27-3).
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CrAccess {
+    /// Number of the control register
+    pub cr: u8,
+    pub access: CrAccessType,
+    /// The LMSW source operand is in memory
+    pub lmsw_memory: bool,
+    /// Encoded index of the general purpose register for MOV CR
+    pub gpr: u8,
+    /// Source data for LMSW
+    pub lmsw_data: u16,
+}
+
+impl CrAccess {
+    pub fn from_qualification(qual: u64) -> CrAccess {
+        let access = match (qual >> 4) & 0x3 {
+            0 => CrAccessType::MovToCr,
+            1 => CrAccessType::MovFromCr,
+            2 => CrAccessType::Clts,
+            _ => CrAccessType::Lmsw,
+        };
+        CrAccess {
+            cr: (qual & 0xf) as u8,
+            access: access,
+            lmsw_memory: (qual & (1 << 6)) != 0,
+            gpr: ((qual >> 8) & 0xf) as u8,
+            lmsw_data: ((qual >> 16) & 0xffff) as u16,
+        }
+    }
+
+    /// Returns the general purpose register operand of a MOV CR, or None if
+    /// 'gpr' doesn't name one. It always does when decoded from a
+    /// qualification, where the field is four bits wide.
+    pub fn gpr_reg(&self) -> Option<vm_reg_name> {
+        vm_reg_name::from_gpr_index(self.gpr)
+    }
+}
+
+/// Exit qualification for I/O instructions (SDM Table 27-5).
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct IoInstruction {
+    /// Size of the access in bytes: 1, 2, or 4
+    pub bytes: u8,
+    /// The instruction is IN or INS, rather than OUT or OUTS
+    pub is_in: bool,
+    /// The instruction is INS or OUTS
+    pub is_string: bool,
+    /// The instruction has a REP prefix
+    pub is_repeat: bool,
+    /// The port is an immediate operand, rather than in DX
+    pub immediate: bool,
+    pub port: u16,
+}
+
+impl IoInstruction {
+    pub fn from_qualification(qual: u64) -> IoInstruction {
+        IoInstruction {
+            bytes: ((qual & 0x7) + 1) as u8,
+            is_in: (qual & (1 << 3)) != 0,
+            is_string: (qual & (1 << 4)) != 0,
+            is_repeat: (qual & (1 << 5)) != 0,
+            immediate: (qual & (1 << 6)) != 0,
+            port: ((qual >> 16) & 0xffff) as u16,
+        }
+    }
+}
+
+/// Exit qualification for EPT violations (SDM Table 27-7).
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct EptViolation {
+    /// The access was a data read
+    pub read: bool,
+    /// The access was a data write
+    pub write: bool,
+    /// The access was an instruction fetch
+    pub fetch: bool,
+    /// The guest physical address was readable
+    pub readable: bool,
+    /// The guest physical address was writable
+    pub writable: bool,
+    /// The guest physical address was executable
+    pub executable: bool,
+    /// The guest linear address field is valid
+    pub gla_valid: bool,
+    /// The access was to the translated linear address, rather than to a
+    /// paging-structure entry. Only meaningful if 'gla_valid' is set.
+    pub gla_translated: bool,
+    /// NMI unblocking due to IRET
+    pub nmi_unblocking: bool,
+}
+
+impl EptViolation {
+    pub fn from_qualification(qual: u64) -> EptViolation {
+        EptViolation {
+            read: (qual & (1 << 0)) != 0,
+            write: (qual & (1 << 1)) != 0,
+            fetch: (qual & (1 << 2)) != 0,
+            readable: (qual & (1 << 3)) != 0,
+            writable: (qual & (1 << 4)) != 0,
+            executable: (qual & (1 << 5)) != 0,
+            gla_valid: (qual & (1 << 7)) != 0,
+            gla_translated: (qual & (1 << 8)) != 0,
+            nmi_unblocking: (qual & (1 << 12)) != 0,
+        }
+    }
+
+    /// Returns the type of the faulting access.
+    pub fn fault_type(&self) -> FaultType {
+        if self.fetch {
+            return FaultType::Execute;
+        } else if self.write {
+            return FaultType::Write;
+        } else {
+            return FaultType::Read;
+        }
+    }
+}
+
+/// Exit qualification for debug exceptions (SDM Table 27-1).
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DebugExceptions {
+    /// Breakpoint conditions B0-B3 that were met, one bit each
+    pub breakpoints: u8,
+    /// A debug register access was detected (BD)
+    pub debug_register_access: bool,
+    /// A single-step trap (BS)
+    pub single_step: bool,
+}
+
+impl DebugExceptions {
+    pub fn from_qualification(qual: u64) -> DebugExceptions {
+        DebugExceptions {
+            breakpoints: (qual & 0xf) as u8,
+            debug_register_access: (qual & (1 << 13)) != 0,
+            single_step: (qual & (1 << 14)) != 0,
+        }
+    }
+}
+
+/// Exit qualification for task switches (SDM Table 27-2).
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TaskSwitch {
+    /// Selector of the new TSS
+    pub tss_selector: u16,
+    pub reason: TaskSwitchReason,
+}
+
+impl TaskSwitch {
+    pub fn from_qualification(qual: u64) -> TaskSwitch {
+        let reason = match (qual >> 30) & 0x3 {
+            0 => TaskSwitchReason::Call,
+            1 => TaskSwitchReason::Iret,
+            2 => TaskSwitchReason::Jmp,
+            _ => TaskSwitchReason::IdtGate,
+        };
+        TaskSwitch {
+            tss_selector: (qual & 0xffff) as u16,
+            reason: reason,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vmx::*;
+
+    #[test]
+    fn test_exit_reason() {
+        assert_eq!(VmxExitReason::from_code(2), VmxExitReason::TripleFault);
+        assert_eq!(VmxExitReason::from_code(48), VmxExitReason::EptViolation);
+        assert_eq!(VmxExitReason::from_code(35), VmxExitReason::Unknown(35));
+        assert_eq!(VmxExitReason::Hlt.code(), 12);
+        assert_eq!(VmxExitReason::Unknown(35).code(), 35);
+
+        // VM-entry failure flag in bit 31
+        assert_eq!(VmxExitReason::from_exit_reason(0x80000021), VmxExitReason::EntryFailGuestState);
+    }
+
+    #[test]
+    fn test_cr_access() {
+        // mov %rax, %cr3
+        let cr = CrAccess::from_qualification(0x03);
+        assert_eq!(cr.cr, 3);
+        assert_eq!(cr.access, CrAccessType::MovToCr);
+        assert_eq!(cr.gpr_reg(), Some(vm_reg_name::VM_REG_GUEST_RAX));
+
+        // mov %cr0, %r9
+        let cr = CrAccess::from_qualification(0x910);
+        assert_eq!(cr.cr, 0);
+        assert_eq!(cr.access, CrAccessType::MovFromCr);
+        assert_eq!(cr.gpr_reg(), Some(vm_reg_name::VM_REG_GUEST_R9));
+
+        // lmsw with a register operand of 0x0011
+        let cr = CrAccess::from_qualification(0x00110030);
+        assert_eq!(cr.access, CrAccessType::Lmsw);
+        assert_eq!(cr.lmsw_memory, false);
+        assert_eq!(cr.lmsw_data, 0x11);
+
+        // A caller-built value with an out of range register index
+        let cr = CrAccess { gpr: 16, ..cr };
+        assert_eq!(cr.gpr_reg(), None);
+    }
+
+    #[test]
+    fn test_io_instruction() {
+        // out %al, $0x80
+        let io = IoInstruction::from_qualification(0x00800040);
+        assert_eq!(io.bytes, 1);
+        assert_eq!(io.is_in, false);
+        assert_eq!(io.immediate, true);
+        assert_eq!(io.port, 0x80);
+
+        // rep insw (%dx), %es:(%rdi) with DX = 0x1f0
+        let io = IoInstruction::from_qualification(0x01f00039);
+        assert_eq!(io.bytes, 2);
+        assert_eq!(io.is_in, true);
+        assert_eq!(io.is_string, true);
+        assert_eq!(io.is_repeat, true);
+        assert_eq!(io.immediate, false);
+        assert_eq!(io.port, 0x1f0);
+    }
+
+    #[test]
+    fn test_ept_violation() {
+        let ept = EptViolation::from_qualification(0x182);
+        assert_eq!(ept.read, false);
+        assert_eq!(ept.write, true);
+        assert_eq!(ept.fetch, false);
+        assert_eq!(ept.readable, false);
+        assert_eq!(ept.gla_valid, true);
+        assert_eq!(ept.gla_translated, true);
+        assert_eq!(ept.fault_type(), FaultType::Write);
+
+        assert_eq!(EptViolation::from_qualification(0x1).fault_type(), FaultType::Read);
+        assert_eq!(EptViolation::from_qualification(0x4).fault_type(), FaultType::Execute);
+    }
+
+    #[test]
+    fn test_debug_and_task_switch() {
+        let db = DebugExceptions::from_qualification(0x4002);
+        assert_eq!(db.breakpoints, 0b0010);
+        assert_eq!(db.single_step, true);
+        assert_eq!(db.debug_register_access, false);
+
+        let ts = TaskSwitch::from_qualification(0xc0000028);
+        assert_eq!(ts.tss_selector, 0x28);
+        assert_eq!(ts.reason, TaskSwitchReason::IdtGate);
+    }
+}